@@ -0,0 +1,250 @@
+use comn::{
+    headless::{self, Client, Heart, SnapshotStats, Update},
+    link::LinkConditions,
+    Chat, ChatScope, PastChat,
+};
+use fxhash::FxHashMap;
+use std::time::{Duration, Instant};
+use turbulence::MessageChannels;
+
+const USAGE: &str = "\
+usage: bot [server address] [options]
+
+options:
+    --bots <n>              how many players to simulate
+    --chat-every <secs>     how often each player chats, to measure latency
+    --report-every <secs>   how often results are printed
+    --secs <secs>           how long to run for, forever if left out
+
+simulating a bad connection for every bot:
+    --latency <ms>          how long each packet takes to arrive
+    --jitter <ms>           how much longer than that it might take
+    --loss <chance>         how likely each packet is to be lost, from 0 to 1
+    --duplicate <chance>    how likely each packet is to arrive twice
+    --reorder <chance>      how likely each packet is to arrive after those sent after it
+    --bandwidth <bytes>     how much each bot can send or receive per second, 0 for no limit";
+
+/// How long a bot waits to hear its own chat back before counting it as lost.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the bots were asked to do on the command line.
+struct Args {
+    server: String,
+    bots: usize,
+    chat_every: Duration,
+    report_every: Duration,
+    run_for: Option<Duration>,
+    link: LinkConditions,
+}
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut me = Self {
+            server: comn::SERVER.to_string(),
+            bots: 10,
+            chat_every: Duration::from_secs(2),
+            report_every: Duration::from_secs(5),
+            run_for: None,
+            link: LinkConditions::default(),
+        };
+
+        let secs = |flag: &str, value: Option<String>| -> Result<Duration, String> {
+            let value = value.ok_or_else(|| format!("{} needs a value\n\n{}", flag, USAGE))?;
+            let secs: f32 = value
+                .parse()
+                .map_err(|e| format!("invalid value {:?} for {}: {}", value, flag, e))?;
+            Ok(Duration::from_secs_f32(secs))
+        };
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                "--bots" => {
+                    let value = args.next().ok_or("--bots needs a value")?;
+                    me.bots = value
+                        .parse()
+                        .map_err(|e| format!("invalid value {:?} for --bots: {}", value, e))?;
+                }
+                "--chat-every" => me.chat_every = secs(&arg, args.next())?,
+                "--report-every" => me.report_every = secs(&arg, args.next())?,
+                "--secs" => me.run_for = Some(secs(&arg, args.next())?),
+                flag if LinkConditions::FLAGS.contains(&flag) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} needs a value\n\n{}", flag, USAGE))?;
+                    me.link.set(flag, &value)?;
+                }
+                other if other.starts_with("--") => {
+                    return Err(format!("unknown option {}\n\n{}", other, USAGE))
+                }
+                _ => me.server = arg,
+            }
+        }
+
+        Ok(me)
+    }
+}
+
+/// A simulated player, wandering around and chatting.
+struct Bot {
+    name: String,
+    client: Client,
+    /// Bots all wander in circles, but each starts off facing a different way
+    heading: f32,
+    last_ping: Instant,
+    next_ping: u32,
+    /// When each chat we're waiting to hear back was sent, by the number in it
+    pings: FxHashMap<u32, Instant>,
+}
+impl Bot {
+    fn new(name: String, client: Client, heading: f32) -> Self {
+        Self {
+            name,
+            client,
+            heading,
+            last_ping: Instant::now(),
+            next_ping: 0,
+            pings: FxHashMap::default(),
+        }
+    }
+
+    /// Returns why the bot was disconnected, if it was.
+    fn update(&mut self, start: Instant, args: &Args, report: &mut Report) -> Option<String> {
+        let Self { name, client, heading, last_ping, next_ping, pings } = self;
+
+        let dir = comn::angle_to_vec(*heading + start.elapsed().as_secs_f32() * 0.5);
+        if let Update::Disconnected(reason) = client.update(dir) {
+            return Some(reason);
+        }
+
+        // the server sends our world chats back to us, so they tell us how long a round trip takes
+        let channel = &mut client.channel;
+        while let Some(Chat { from, text, .. }) = channel.recv::<Chat>() {
+            if from != *name {
+                continue;
+            }
+            let sent = text.strip_prefix("ping ").and_then(|n| n.parse().ok());
+            if let Some(sent_at) = sent.and_then(|n| pings.remove(&n)) {
+                report.latencies.push(sent_at.elapsed());
+            }
+        }
+        while let Some(PastChat { .. }) = channel.recv() {}
+
+        pings.retain(|_, sent_at| {
+            let lost = sent_at.elapsed() > PING_TIMEOUT;
+            report.pings_lost += lost as u32;
+            !lost
+        });
+        if last_ping.elapsed() >= args.chat_every {
+            *last_ping = Instant::now();
+            *next_ping += 1;
+            pings.insert(*next_ping, Instant::now());
+            comn::send_or_err(channel, Chat::new(ChatScope::World, format!("ping {}", next_ping)));
+        }
+
+        channel.flush_all();
+        None
+    }
+}
+
+/// What the bots have measured since the last report.
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    pings_lost: u32,
+}
+impl Report {
+    fn print(&mut self, joining: usize, bots: &[Bot], snapshots: SnapshotStats) {
+        let ms = |d: &Duration| d.as_secs_f32() * 1000.0;
+        let avg = self.latencies.iter().map(ms).sum::<f32>() / self.latencies.len().max(1) as f32;
+        let max = self.latencies.iter().map(ms).fold(0.0, f32::max);
+        let total = (snapshots.complete + snapshots.missed).max(1);
+
+        println!(
+            "{} playing, {} joining | chat round trip: avg {:.1}ms, max {:.1}ms, {} lost | \
+             snapshots since joining: {} complete, {} missed ({:.1}% loss)",
+            bots.len(),
+            joining,
+            avg,
+            max,
+            self.pings_lost,
+            snapshots.complete,
+            snapshots.missed,
+            snapshots.missed as f32 / total as f32 * 100.0,
+        );
+        *self = Self::default();
+    }
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // the process id keeps bots from different runs from asking for the same names
+    let prefix = format!("bot{}", std::process::id() % 1000);
+    let mut joining: Vec<(String, MessageChannels, Heart)> = (0..args.bots)
+        .map(|i| {
+            let name = format!("{}_{}", prefix, i);
+            (name.clone(), headless::connect(&args.server, &name, None, args.link), Heart::new())
+        })
+        .collect();
+    let mut bots: Vec<Bot> = Vec::with_capacity(args.bots);
+    let mut report = Report::default();
+
+    let start = Instant::now();
+    let mut last_report = Instant::now();
+    while args.run_for.map_or(true, |secs| start.elapsed() < secs) {
+        let mut i = 0;
+        while i < joining.len() {
+            let (_, channel, heart) = &mut joining[i];
+            match headless::poll_join(channel, heart) {
+                None => i += 1,
+                Some(result) => {
+                    let (name, channel, heart) = joining.swap_remove(i);
+                    match result {
+                        Ok(join) => {
+                            let heading = bots.len() as f32;
+                            bots.push(Bot::new(name, Client::new(channel, heart, join), heading));
+                        }
+                        Err(reason) => log::error!("{} was rejected: {}", name, reason),
+                    }
+                }
+            }
+        }
+
+        let mut i = 0;
+        while i < bots.len() {
+            match bots[i].update(start, &args, &mut report) {
+                None => i += 1,
+                Some(reason) => {
+                    let bot = bots.swap_remove(i);
+                    log::error!("{} was disconnected: {}", bot.name, reason);
+                }
+            }
+        }
+
+        if last_report.elapsed() >= args.report_every {
+            last_report = Instant::now();
+            let snapshots = bots.iter().map(|b| b.client.world.ents.snapshot_stats()).fold(
+                SnapshotStats::default(),
+                |sum, s| SnapshotStats {
+                    complete: sum.complete + s.complete,
+                    missed: sum.missed + s.missed,
+                },
+            );
+            report.print(joining.len(), &bots, snapshots);
+        }
+
+        std::thread::sleep(Duration::from_millis(16));
+    }
+
+    for bot in &mut bots {
+        bot.client.leave("bot finished");
+    }
+    // give the goodbyes a moment to get out
+    std::thread::sleep(Duration::from_millis(500));
+}
@@ -1,67 +1,169 @@
-use comn::Chat;
+use chrono::{DateTime, Local};
+use comn::{Chat, ChatScope, PastChat, TravelTo};
 use macroquad::prelude::*;
+use std::collections::VecDeque;
 use turbulence::MessageChannels;
 
-const MAX_MESSAGES: usize = 100;
+/// How a Chat is written in the ChatBox, so that each scope is easy to tell apart.
+fn describe(Chat { scope, from, text }: &Chat) -> String {
+    match scope {
+        ChatScope::World => format!("{}: {}", from, text),
+        ChatScope::Global => format!("[global] {}: {}", from, text),
+        ChatScope::Whisper(to) => format!("[{} -> {}] {}", from, to, text),
+        ChatScope::System => format!("* {}", text),
+    }
+}
+
+/// A line written in the ChatBox, stamped with the local time it was written at.
+struct Line {
+    stamp: String,
+    text: String,
+}
+impl Line {
+    fn new(time: DateTime<Local>, text: String) -> Self {
+        Self { stamp: time.format("%H:%M").to_string(), text }
+    }
+
+    fn matches(&self, filter: &str) -> bool {
+        filter.is_empty() || self.text.to_lowercase().contains(&filter.to_lowercase())
+    }
+}
+
 pub struct ChatBox {
-    /// All of the messages from the server
-    history: [String; MAX_MESSAGES],
-    /// Where new messages from the server should be written to
-    pen: usize,
+    /// The newest messages from the server, and anything else worth telling the user
+    history: VecDeque<Line>,
+    /// How many lines are kept in `history` before the oldest are forgotten
+    history_len: usize,
+    /// Only lines containing this are shown, unless it's empty
+    filter: String,
+    /// What the user has sent before, oldest first, so they can bring it back with the arrow keys
+    sent: VecDeque<String>,
+    /// Which of the `sent` messages is in `wip_message`, if one is
+    recalled: Option<usize>,
     /// The message the user is preparing to send
     wip_message: String,
     send_wip: bool,
     jump_to_bottom: bool,
+    /// Whether the user last clicked on the ChatBox, so that the keys they press are meant for it
+    focused: bool,
 }
 impl ChatBox {
-    pub fn new() -> Self {
+    pub fn new(history_len: usize) -> Self {
         Self {
-            history: [(); MAX_MESSAGES].map(|_| Default::default()),
-            pen: 1,
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            filter: String::new(),
+            sent: VecDeque::with_capacity(history_len),
+            recalled: None,
             wip_message: String::with_capacity(500),
             send_wip: false,
             jump_to_bottom: false,
+            focused: false,
         }
     }
 
     pub fn log_message(&mut self, message: String) {
-        let Self { pen, history, .. } = self;
-        history[*pen] = message;
-        *pen += 1;
-        *pen = if *pen == MAX_MESSAGES { 0 } else { *pen };
+        self.log_message_at(Local::now(), message);
+    }
+
+    fn log_message_at(&mut self, time: DateTime<Local>, message: String) {
+        let Self { history, history_len, .. } = self;
+        history.push_back(Line::new(time, message));
+        while history.len() > *history_len {
+            history.pop_front();
+        }
+    }
+
+    /// Swaps the message being written for one sent before, or back again.
+    /// `older` is true to go further back in time, false to come forward.
+    fn recall(&mut self, older: bool) {
+        let Self { sent, recalled, wip_message, .. } = self;
+        *recalled = match (*recalled, older) {
+            // nothing to come forward to, and what they're writing shouldn't be lost
+            (None, false) => return,
+            (None, true) => sent.len().checked_sub(1),
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) => Some(i + 1).filter(|&i| i < sent.len()),
+        };
+        *wip_message = recalled.and_then(|i| sent.get(i)).cloned().unwrap_or_default();
     }
 
     pub fn sync_messages(&mut self, channels: &mut MessageChannels) {
-        while let Some(Chat(chat)) = channels.recv() {
-            self.log_message(chat);
+        while let Some(chat) = channels.recv::<Chat>() {
+            self.log_message(describe(&chat));
+        }
+        while let Some(PastChat { age_secs, chat }) = channels.recv() {
+            let said_at = Local::now() - chrono::Duration::seconds(age_secs as i64);
+            self.log_message_at(said_at, describe(&chat));
         }
 
-        let Self { send_wip, wip_message, .. } = self;
-        if *send_wip {
-            *send_wip = false;
-            comn::send_or_err(channels, Chat(std::mem::take(wip_message)));
+        if self.send_wip {
+            self.send_wip = false;
+            self.recalled = None;
+            let message = std::mem::take(&mut self.wip_message);
+            if self.sent.back() != Some(&message) {
+                self.sent.push_back(message.clone());
+                if self.sent.len() > self.history_len {
+                    self.sent.pop_front();
+                }
+            }
+            if let Some(world) = message.strip_prefix("/travel ") {
+                comn::send_or_err(channels, TravelTo(world.trim().to_string()));
+            } else if let Some(text) = message.strip_prefix("/g ") {
+                comn::send_or_err(channels, Chat::new(ChatScope::Global, text));
+            } else if let Some(rest) = message.strip_prefix("/w ") {
+                let mut words = rest.trim_start().splitn(2, ' ');
+                match (words.next(), words.next()) {
+                    (Some(to), Some(text)) if !text.trim().is_empty() => comn::send_or_err(
+                        channels,
+                        Chat::new(ChatScope::Whisper(to.to_string()), text.trim()),
+                    ),
+                    _ => self.log_message("* usage: /w <name> <message>".to_string()),
+                }
+            } else {
+                comn::send_or_err(channels, Chat::new(ChatScope::World, message));
+            }
         }
     }
 
+    /// Returns true if what the user types is meant for the ChatBox, rather than for steering.
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
     pub fn ui(&mut self) {
         use megaui::{hash, widgets::Group, Layout, Vector2};
         use megaui_macroquad::{draw_window, megaui, WindowParams};
-        let Self { send_wip, jump_to_bottom, wip_message, pen, history, .. } = self;
 
         const CHAT_WIDTH: f32 = 400.0;
+        const CHAT_HEIGHT: f32 = 225.0;
+
+        if is_mouse_button_pressed(MouseButton::Left) {
+            let (x, y) = mouse_position();
+            self.focused = x <= CHAT_WIDTH && y <= CHAT_HEIGHT;
+        }
+        if self.focused && is_key_pressed(KeyCode::Up) {
+            self.recall(true);
+        }
+        if self.focused && is_key_pressed(KeyCode::Down) {
+            self.recall(false);
+        }
+
+        let Self { send_wip, jump_to_bottom, wip_message, filter, history, .. } = self;
+
         draw_window(
             hash!(),
             Vec2::zero(),
-            vec2(CHAT_WIDTH, 200.0),
-            WindowParams { label: "chat".to_string(), ..Default::default() },
+            vec2(CHAT_WIDTH, CHAT_HEIGHT),
+            // kept in place, so that clicks can be told to be on it, see `focused`
+            WindowParams { label: "chat".to_string(), movable: false, ..Default::default() },
             |ui| {
                 let mut jump_to_bottom_button = false;
                 Group::new(hash!(), Vector2::new(CHAT_WIDTH, 160.0))
                     .layout(Layout::Free(Vector2::new(0.0, 0.0)))
                     .ui(ui, |ui| {
-                        for msg_i in (*pen..MAX_MESSAGES).chain(0..*pen) {
-                            let msg = &*history[msg_i].trim();
-                            ui.label(None, msg);
+                        for line in history.iter().filter(|line| line.matches(filter)) {
+                            ui.label(None, &format!("[{}] {}", line.stamp, line.text.trim()));
                         }
                         if ui.frame == 1 || *jump_to_bottom {
                             ui.scroll_here();
@@ -78,6 +180,10 @@ impl ChatBox {
 
                 Group::new(hash!(), Vector2::new(CHAT_WIDTH, 25.0))
                     .layout(Layout::Free(Vector2::new(0.0, 160.0)))
+                    .ui(ui, |ui| ui.input_text(hash!(), "<- search", filter));
+
+                Group::new(hash!(), Vector2::new(CHAT_WIDTH, 25.0))
+                    .layout(Layout::Free(Vector2::new(0.0, 185.0)))
                     .ui(ui, |ui| ui.input_text(hash!(), "<- msg", wip_message));
 
                 if is_key_pressed(KeyCode::Enter) && !wip_message.is_empty() {
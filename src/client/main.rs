@@ -1,11 +1,13 @@
-#![feature(array_map)]
-use comn::Heartbeat;
+use comn::{
+    headless::{self, Client, Heart, Update},
+    link::LinkConditions,
+};
 use macroquad::prelude::*;
-use std::time::Instant;
-use turbulence::MessageChannels;
 
 mod chat;
 use chat::ChatBox;
+mod overlay;
+use overlay::Overlay;
 
 #[derive(Debug, Copy, Clone)]
 struct Sprite {
@@ -13,11 +15,13 @@ struct Sprite {
     art: comn::Art,
 }
 impl Sprite {
-    fn new(art: comn::Art) -> Self {
+    /// Islands come in a few varieties, which one is picked by the id
+    /// of the entity so that it looks the same every time it's drawn.
+    fn new(art: comn::Art, id: u64) -> Self {
         Self {
             art,
             rect: match art {
-                comn::Art::Island => match rand::rand() % 3 {
+                comn::Art::Island => match id % 3 {
                     0 => Rect { x: 000.0, y: 000.0, w: 256.0, h: 256.0 },
                     1 => Rect { x: 000.0, y: 256.0, w: 256.0, h: 256.0 },
                     2 => Rect { x: 256.0, y: 000.0, w: 256.0, h: 256.0 },
@@ -27,72 +31,10 @@ impl Sprite {
             },
         }
     }
-}
-
-const FRAMES_SAVED: usize = 5;
-#[derive(Debug, Copy, Clone)]
-struct Ent {
-    pos_frames: [(u32, Vec2); FRAMES_SAVED],
-    sprite: Sprite,
-}
-impl Ent {
-    fn new(pos: Vec2, art: comn::Art) -> Self {
-        Self { pos_frames: [(0, pos); FRAMES_SAVED], sprite: Sprite::new(art) }
-    }
 
-    fn pos_lerp(&self, (tick, time): (u32, f32)) -> Vec2 {
-        let pfs = self.pos_frames;
-        let sim_time = (tick.saturating_sub(2), time);
-        let tween_frames = pfs
-            .iter()
-            .position(|&(t, _)| t <= sim_time.0)
-            .and_then(|l| Some([pfs.get(l - 1)?, pfs.get(l)?]));
-
-        if let Some([&(t1, p1), &(t2, p2)]) = tween_frames {
-            let expected = (t1 - t2) as f32;
-            if expected > 1.0 {
-                dbg!(expected, get_time());
-            }
-            let elapsed = (sim_time.0 - t2) as f32 + sim_time.1;
-            p2.lerp(p1, elapsed / expected)
-        } else {
-            dbg!("no interp, no data :(", self.pos_frames, sim_time);
-            self.pos_frames[0].1
-        }
-    }
-}
-
-struct Ents {
-    pub ents: fxhash::FxHashMap<u64, Ent>,
-}
-impl Ents {
-    pub fn new(mut islands: Vec<(u64, Vec2, comn::Art)>) -> Self {
-        use {fxhash::FxBuildHasher, std::collections::HashMap};
-        let mut ents = HashMap::with_capacity_and_hasher(1000, FxBuildHasher::default());
-        ents.extend(islands.drain(..).map(|(i, p, a)| (i, Ent::new(p, a))));
-        Self { ents }
-    }
-
-    pub fn poll_messages(&mut self, channels: &mut MessageChannels) {
-        use comn::{EntEvent, Move};
-        let Self { ents, .. } = self;
-        while let Some(e) = channels.recv() {
-            match dbg!(e) {
-                EntEvent::Spawn(id, pos, art) => ents.insert(id, Ent::new(pos, art)),
-                EntEvent::Despawn(id) => ents.remove(&id),
-            };
-        }
-        while let Some(Move { id, tick, pos }) = channels.recv() {
-            if let Some(Ent { pos_frames, .. }) = ents.get_mut(&id) {
-                let (last_tick, _) = pos_frames[0];
-                if tick > last_tick {
-                    let mut keep_frames = [Default::default(); FRAMES_SAVED - 1];
-                    keep_frames.copy_from_slice(&pos_frames[0..FRAMES_SAVED - 1]);
-                    pos_frames[1..FRAMES_SAVED].copy_from_slice(&keep_frames);
-                    pos_frames[0] = (tick, pos);
-                }
-            }
-        }
+    /// The Sprite for an entity with these components, if it has any Art.
+    fn of(id: u64, comps: &[comn::Comp]) -> Option<Self> {
+        comn::get::<comn::Art>(comps).map(|&art| Self::new(art, id))
     }
 }
 
@@ -105,13 +47,18 @@ impl Drawer {
         Self { atlas: load_texture("atlas.png").await }
     }
 
-    pub fn draw(&self, arts: impl Iterator<Item = (Vec2, Sprite)>) {
+    pub fn draw<'a>(
+        &self,
+        arts: impl Iterator<Item = (Vec2, Sprite)>,
+        labels: impl Iterator<Item = (Vec2, &'a str)>,
+    ) {
         use macroquad::prelude::*;
 
-        set_camera(Camera2D {
+        let camera = Camera2D {
             zoom: vec2(1.0, screen_width() / screen_height()) / 3.2,
             ..Default::default()
-        });
+        };
+        set_camera(camera);
 
         clear_background(Color([180, 227, 245, 255]));
 
@@ -132,6 +79,14 @@ impl Drawer {
         }
 
         set_default_camera();
+
+        // text is drawn in screen space so that it stays crisp and the same size
+        const FONT_SIZE: f32 = 20.0;
+        for (pos, label) in labels {
+            let above = camera.world_to_screen(pos + vec2(0.0, 0.6));
+            let width = label.chars().count() as f32 * FONT_SIZE / 4.0;
+            draw_text(label, above.x() - width, above.y(), FONT_SIZE, BLACK);
+        }
     }
 }
 
@@ -140,96 +95,123 @@ fn loading_text(t: &'static str) {
     draw_text(t, 20.0, 20.0, 40.0, WHITE);
 }
 
-struct Heart(Instant);
-impl Heart {
-    fn new() -> Self {
-        Self(Instant::now() - std::time::Duration::from_secs(1))
-    }
-
-    fn beat(&mut self, channel: &mut MessageChannels) {
-        if self.0.elapsed().as_secs_f32() > 0.2 {
-            self.0 = Instant::now();
-            channel.send(Heartbeat);
-        }
-    }
+/// The direction the player is pressing keys to move in, one unit long at most on either axis.
+fn input_dir() -> Vec2 {
+    let axis = |pos: &[KeyCode], neg: &[KeyCode]| {
+        let held = |keys: &[KeyCode]| keys.iter().any(|&k| is_key_down(k)) as i32;
+        (held(pos) - held(neg)) as f32
+    };
+    vec2(
+        axis(&[KeyCode::D, KeyCode::Right], &[KeyCode::A, KeyCode::Left]),
+        axis(&[KeyCode::W, KeyCode::Up], &[KeyCode::S, KeyCode::Down]),
+    )
 }
 
-struct Clock {
-    tick: (u32, f32),
-    last_tick_taken: Instant,
+struct Game {
+    /// What the server knows us as, see `comn::Name`
+    name: String,
+    client: Client,
+    chat_box: ChatBox,
+    overlay: Overlay,
+    drawer: Drawer,
 }
-impl Clock {
-    fn new(tick: u32) -> Self {
-        Self { tick: (tick, 0.0), last_tick_taken: Instant::now() }
+impl Game {
+    async fn new(name: String, client: Client, mut chat_box: ChatBox) -> Self {
+        chat_box.log_message(format!("Welcome to {}!", client.world.name));
+        Self { name, client, chat_box, overlay: Overlay::new(), drawer: Drawer::new().await }
     }
 
-    fn tick(&mut self) -> (u32, f32) {
-        let Self { tick: (tick, left), last_tick_taken } = self;
-        *left += last_tick_taken.elapsed().as_secs_f32() / (0.001 * comn::SERVER_TICK_MS as f32);
-        *last_tick_taken = Instant::now();
+    /// Returns the reason the game ended, if it has.
+    fn update(&mut self) -> Option<String> {
+        let Self { name, client, chat_box, overlay, drawer } = self;
 
-        let delta = *left - (*left - left.floor());
-        if delta >= 1.0 {
-            *tick += delta as u32;
-            *left -= delta;
+        // keys pressed while typing a chat are meant for the ChatBox, not for steering or leaving
+        if is_key_pressed(KeyCode::Escape) && !chat_box.is_focused() {
+            client.leave("left the game");
+            return Some("you left the game".to_string());
         }
+        let dir = if chat_box.is_focused() { Vec2::zero() } else { input_dir() };
+        match client.update(dir) {
+            Update::Stayed => {}
+            Update::Arrived => chat_box.log_message(format!("Welcome to {}!", client.world.name)),
+            Update::Disconnected(reason) => return Some(reason),
+        }
+        chat_box.sync_messages(&mut client.channel);
+        client.channel.flush_all();
+
+        let headless::World { ents, you, your_island, your_comps, time, .. } = &client.world;
+        let your_pos = you.pos_lerp(time.1);
+        let complete = ents.complete_tick();
+        let you = Sprite::of(*your_island, your_comps).map(|sprite| (your_pos, sprite));
+        let others = ents
+            .ents
+            .iter()
+            .filter_map(|(&id, e)| Some((e.pos_lerp(*time, complete), Sprite::of(id, &e.comps)?)));
+        let labels = ents.ents.values().filter_map(|e| {
+            let comn::Name(label) = comn::get::<comn::Name>(&e.comps)?;
+            Some((e.pos_lerp(*time, complete), label.as_str()))
+        });
+        drawer.draw(others.chain(you), labels.chain(Some((your_pos, name.as_str()))));
+        chat_box.ui();
+        overlay.ui(client);
 
-        (*tick, *left)
-    }
-}
-
-#[test]
-fn clock() {
-    let mut one_tick_clock = Clock::new(100);
-    let mut clock = Clock::new(100);
-
-    for i in 0..100 {
-        std::thread::sleep(std::time::Duration::from_millis(comn::SERVER_TICK_MS as _));
-        clock.tick();
+        None
     }
-
-    assert_eq!(clock.tick(), one_tick_clock.tick());
 }
 
-struct Game {
-    ents: Ents,
-    channel: MessageChannels,
-    heart: Heart,
-    chat_box: ChatBox,
-    drawer: Drawer,
-    clock: Clock,
+/// What the client was asked to do on the command line, i.e.
+/// `client [server address] [name] [--chat-history <lines>] [--op-token <token>]`,
+/// along with any of `LinkConditions::FLAGS` to simulate a bad connection, like `--latency 100`
+struct Args {
+    server: String,
+    name: String,
+    /// How many lines the ChatBox remembers
+    chat_history: usize,
+    /// The secret that makes us an op on the server, if we have one
+    op_token: Option<String>,
+    link: LinkConditions,
 }
-impl Game {
-    async fn new(channel: MessageChannels, heart: Heart, intro: comn::WorldJoin) -> Self {
-        let comn::WorldJoin { your_island, world_name, islands, tick } = intro;
-
-        let mut ents = Ents::new(islands);
-        ents.ents.remove(&your_island); // DELETE ME PLS
-
-        let mut chat_box = ChatBox::new();
-        chat_box.log_message(format!("Welcome to {}!", world_name));
-
-        Self {
-            ents,
-            channel,
-            heart,
-            chat_box,
-            drawer: Drawer::new().await,
-            clock: Clock::new(tick),
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut positional = Vec::with_capacity(2);
+        let mut chat_history = 100;
+        let mut op_token = None;
+        let mut link = LinkConditions::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--chat-history" => {
+                    let value = args.next().ok_or("--chat-history needs a value")?;
+                    chat_history = value.parse().map_err(|e| {
+                        format!("invalid value {:?} for --chat-history: {}", value, e)
+                    })?;
+                }
+                "--op-token" => {
+                    op_token = Some(args.next().ok_or("--op-token needs a value")?);
+                }
+                flag if LinkConditions::FLAGS.contains(&flag) => {
+                    let value = args.next().ok_or_else(|| format!("{} needs a value", flag))?;
+                    link.set(flag, &value)?;
+                }
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                _ if positional.len() == 2 => return Err(format!("unexpected argument {:?}", arg)),
+                _ => positional.push(arg),
+            }
         }
-    }
-
-    fn update(&mut self) {
-        let Self { heart, chat_box, ents, channel, drawer, clock } = self;
-        let time = clock.tick();
 
-        heart.beat(channel);
-        chat_box.sync_messages(channel);
-        ents.poll_messages(channel);
-        channel.flush_all();
+        let mut positional = positional.into_iter();
+        let server = positional.next().unwrap_or_else(|| comn::SERVER.to_string());
+        let name = positional.next().unwrap_or_else(|| {
+            // quad-rand starts from the same seed every time, so every client would pick the same
+            use std::time::{SystemTime, UNIX_EPOCH};
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            rand::srand(now.as_nanos() as u64 ^ std::process::id() as u64);
+            format!("sailor{}", rand::rand() % 10000)
+        });
+        if let Some(problem) = comn::Name::problem(&name) {
+            return Err(format!("can't go by {:?}: {}", name, problem));
+        }
 
-        drawer.draw(ents.ents.values().map(|e| (e.pos_lerp(time), e.sprite)));
-        chat_box.ui();
+        Ok(Self { server, name, chat_history, op_token, link })
     }
 }
 
@@ -247,69 +229,54 @@ async fn main() {
     sapp_console_log::init().unwrap();
     #[cfg(not(target_arch = "wasm32"))]
     pretty_env_logger::init();
+    // so that the server can be told we're leaving when the window is closed
+    prevent_quit();
+
+    let Args { server, name, chat_history, op_token, link } =
+        match Args::parse(std::env::args().skip(1)) {
+            Ok(args) => args,
+            Err(e) => return stranded(e).await,
+        };
 
-    let mut channel = direct_socket(comn::CLIENT, comn::SERVER, 1024);
+    let mut channel = headless::connect(&server, &name, op_token.as_deref(), link);
     let mut heart = Heart::new();
-    let intro = loop {
-        if let Some(intro) = channel.recv() {
-            break intro;
+    let join = loop {
+        match headless::poll_join(&mut channel, &mut heart) {
+            Some(Ok(join)) => break join,
+            Some(Err(reason)) => return stranded(format!("rejected by server: {}", reason)).await,
+            None => {}
         }
 
-        heart.beat(&mut channel);
-        channel.flush::<Heartbeat>();
-
+        if is_quit_requested() {
+            return;
+        }
         loading_text("connecting to server ...");
         next_frame().await;
     };
-    let mut game = Game::new(channel, heart, intro).await;
+    let client = Client::new(channel, heart, join);
+    let mut game = Game::new(name, client, ChatBox::new(chat_history)).await;
 
-    loop {
-        game.update();
+    let reason = loop {
+        if is_quit_requested() {
+            game.client.leave("closed the game");
+            return;
+        }
+        if let Some(reason) = game.update() {
+            break reason;
+        }
         megaui_macroquad::draw_megaui();
 
         next_frame().await;
-    }
-}
-
-// Returns a MessageChannels corresponding to a UDP socket that only accepts messages from,
-// and sends messages to, a single address.
-fn direct_socket(
-    my_addr: &'static str,
-    remote_addr: &'static str,
-    pool_size: usize,
-) -> MessageChannels {
-    use comn::net::{
-        acquire_max, channel_with_multiplexer, send_outgoing_to_socket, SimpleBufferPool,
     };
-    use turbulence::{BufferPacketPool, Packet};
-
-    let pool = BufferPacketPool::new(SimpleBufferPool(pool_size));
-    let (channel, multiplexer) = channel_with_multiplexer(pool.clone());
-
-    let socket = smol::block_on(async {
-        let s = smol::net::UdpSocket::bind(my_addr).await.expect("couldn't bind to address");
-        s.connect(remote_addr).await.expect("connect function failed");
-        s
-    });
-
-    let (mut incoming, outgoing) = multiplexer.start();
-    send_outgoing_to_socket(outgoing, socket.clone(), remote_addr.parse().unwrap());
-
-    smol::spawn(async move {
-        loop {
-            let mut packet = acquire_max(&pool);
-            match socket.recv(&mut packet).await {
-                Ok(len) => {
-                    packet.truncate(len);
-                    if let Err(e) = incoming.try_send(packet) {
-                        error!("couldn't send packet: {}", e);
-                    }
-                }
-                Err(e) => error!("couldn't recieve packet from UDP socket: {}", e),
-            };
-        }
-    })
-    .detach();
+    stranded(format!("disconnected: {}", reason)).await;
+}
 
-    channel
+/// Displays a message until the window is closed,
+/// for when there's nothing left to do but explain what went wrong.
+async fn stranded(message: String) {
+    while !is_quit_requested() {
+        clear_background(BLACK);
+        draw_text(&message, 20.0, 20.0, 40.0, WHITE);
+        next_frame().await;
+    }
 }
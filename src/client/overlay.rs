@@ -0,0 +1,127 @@
+use comn::{headless::Client, Traffic};
+use macroquad::prelude::*;
+use std::time::Instant;
+
+/// How many entities' interpolation buffers are listed, so the overlay doesn't run off the screen.
+const ENTS_LISTED: usize = 12;
+
+/// Network stats drawn next to the ChatBox, shown and hidden with F3.
+pub struct Overlay {
+    shown: bool,
+    /// The traffic on each channel when the rates were last worked out, and when that was
+    counted: (Instant, Vec<Traffic>),
+    /// The bytes per second received and sent on each channel, by name
+    rates: Vec<(&'static str, f32, f32)>,
+}
+impl Overlay {
+    pub fn new() -> Self {
+        Self { shown: false, counted: (Instant::now(), comn::traffic()), rates: vec![] }
+    }
+
+    /// Bytes per second in and out on each channel, worked out about once a second.
+    fn update_rates(&mut self) {
+        let Self { counted: (at, before), rates, .. } = self;
+        let secs = at.elapsed().as_secs_f32();
+        if secs < 1.0 {
+            return;
+        }
+
+        let now = comn::traffic();
+        *rates = now
+            .iter()
+            .zip(before.iter())
+            .map(|(now, before)| {
+                let rate = |now: u64, before: u64| (now - before) as f32 / secs;
+                (
+                    now.name,
+                    rate(now.bytes_received, before.bytes_received),
+                    rate(now.bytes, before.bytes),
+                )
+            })
+            .filter(|&(_, bytes_in, bytes_out)| bytes_in > 0.0 || bytes_out > 0.0)
+            .collect();
+        *at = Instant::now();
+        *before = now;
+    }
+
+    fn lines(&self, client: &Client) -> Vec<String> {
+        let Client { world, rtt, heart, .. } = client;
+        let mut lines = Vec::with_capacity(32);
+
+        let rtt = rtt.map_or("?".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+        lines.push(format!("rtt: {}, heartbeat sent {}ms ago", rtt, heart.age().as_millis()));
+
+        let stats = world.ents.snapshot_stats();
+        let total = (stats.complete + stats.missed).max(1);
+        lines.push(format!(
+            "snapshots: {} complete, {} missed ({:.1}% loss)",
+            stats.complete,
+            stats.missed,
+            stats.missed as f32 / total as f32 * 100.0
+        ));
+
+        let (tick, into) = world.time;
+        let newest = world.ents.newest_tick();
+        lines.push(format!(
+            "tick: clock {:.2}, server {} ({:+.2})",
+            tick as f32 + into,
+            newest,
+            newest as f32 - (tick as f32 + into)
+        ));
+        lines.push(format!(
+            "clock sync: aiming {:.1} ticks behind, {:+.2} left to correct",
+            world.clock.delay,
+            world.clock.correction()
+        ));
+        lines.push(format!("snapshot jitter: {:.2} ticks", world.ents.jitter()));
+
+        lines.push("bytes/s per channel: in, out".to_string());
+        for (name, bytes_in, bytes_out) in &self.rates {
+            lines.push(format!("  {}: {:.0}, {:.0}", name, bytes_in, bytes_out));
+        }
+
+        let mut ents: Vec<_> = world.ents.ents.iter().collect();
+        ents.sort_unstable_by_key(|&(&id, _)| id);
+        lines.push(format!("interpolation buffer, in ticks ({} ents)", ents.len()));
+        for (id, ent) in ents.into_iter().take(ENTS_LISTED) {
+            let state = if ent.starved(world.time) {
+                " starved!"
+            } else if ent.extrapolating(world.time, world.ents.complete_tick()) {
+                " late"
+            } else if ent.gapped(world.time) {
+                " gap"
+            } else {
+                ""
+            };
+            lines.push(format!("  #{}: {:.2}{}", id, ent.buffered(world.time), state));
+        }
+
+        lines
+    }
+
+    pub fn ui(&mut self, client: &Client) {
+        use megaui::hash;
+        use megaui_macroquad::{draw_window, WindowParams};
+
+        if is_key_pressed(KeyCode::F3) {
+            self.shown = !self.shown;
+        }
+        if !self.shown {
+            return;
+        }
+
+        self.update_rates();
+        let lines = self.lines(client);
+        draw_window(
+            hash!(),
+            vec2(410.0, 0.0),
+            vec2(320.0, 400.0),
+            WindowParams { label: "network (F3)".to_string(), ..Default::default() },
+            |ui| {
+                for line in &lines {
+                    ui.label(None, line);
+                }
+            },
+        );
+    }
+}
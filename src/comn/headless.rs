@@ -0,0 +1,938 @@
+use crate::{
+    link::LinkConditions, predict::Predictor, send_or_err, Comp, CompKind, Disconnect,
+    HandshakeReply, Heartbeat, Ping, Pong, TimeReply, TimeRequest, WorldEvent, WorldJoin,
+};
+use glam::Vec2;
+use std::time::{Duration, Instant};
+use turbulence::MessageChannels;
+
+/// How many ticks behind the newest positions from the server entities are drawn,
+/// until there's been a chance to measure how evenly Snapshots arrive.
+pub const INTERP_DELAY_TICKS: u32 = 2;
+/// The fewest ticks behind the newest positions entities are drawn, however evenly they arrive.
+const MIN_DELAY_TICKS: f32 = 1.0;
+/// The most ticks behind the newest positions entities are drawn, however unevenly they arrive.
+const MAX_DELAY_TICKS: f32 = 10.0;
+/// How many times the jitter to draw entities behind the newest positions, on top of the minimum.
+const JITTER_MARGIN: f32 = 4.0;
+/// How many of the newest positions each Ent remembers, for interpolating between.
+const FRAMES_SAVED: usize = MAX_DELAY_TICKS as usize + 2;
+/// How far past its newest position an entity is carried along when its positions are late.
+const MAX_EXTRAPOLATE_TICKS: f32 = 3.0;
+/// How long it takes to smooth over an entity's jump when its positions are corrected.
+const BLEND_TICKS: f32 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct Ent {
+    pub pos_frames: [(u32, Vec2); FRAMES_SAVED],
+    pub comps: Vec<Comp>,
+    /// How far the entity jumped the last time its positions were corrected, and when,
+    /// so that the jump can be smoothed over
+    blend: (Vec2, (u32, f32)),
+}
+impl Ent {
+    /// An entity that was at `pos` on `tick`.
+    fn new(tick: u32, pos: Vec2, comps: Vec<Comp>) -> Self {
+        let mut ent = Self {
+            pos_frames: [(tick, pos); FRAMES_SAVED],
+            comps: vec![],
+            blend: (Vec2::zero(), (0, 0.0)),
+        };
+        ent.update(comps);
+        ent
+    }
+
+    /// Adds the given components, replacing any of the same kind.
+    fn update(&mut self, comps: Vec<Comp>) {
+        for comp in comps {
+            match self.comps.iter_mut().find(|c| c.kind() == comp.kind()) {
+                Some(old) => *old = comp,
+                None => self.comps.push(comp),
+            }
+        }
+    }
+
+    fn remove(&mut self, kinds: &[CompKind]) {
+        self.comps.retain(|c| !kinds.contains(&c.kind()));
+    }
+
+    /// Remembers where the entity was on this tick, if it's newer than anything we have.
+    fn push_frame(&mut self, tick: u32, pos: Vec2) {
+        let pfs = &mut self.pos_frames;
+        if tick > pfs[0].0 {
+            pfs.rotate_right(1);
+            pfs[0] = (tick, pos);
+        }
+    }
+
+    /// Makes a change that might move where the entity is drawn at this point in time,
+    /// starting a blend from where it was drawn to where it should be if it does.
+    /// `complete` and `now_complete` are the newest complete ticks before and after the change.
+    fn correct(
+        &mut self,
+        time: (u32, f32),
+        complete: u32,
+        now_complete: u32,
+        change: impl FnOnce(&mut Self),
+    ) {
+        let drawn = self.pos_lerp(time, complete);
+        let was = self.raw_pos(time, complete);
+        change(self);
+        let is = self.raw_pos(time, now_complete);
+        if is != was {
+            self.blend = (drawn - is, time);
+        }
+    }
+
+    /// The index of the older of the two positions to interpolate between at this point in time,
+    /// if we have positions from both before and after it.
+    fn tween_index(&self, (tick, _): (u32, f32)) -> Option<usize> {
+        let l = self.pos_frames.iter().position(|&(t, _)| t <= tick)?;
+        l.checked_sub(1).map(|_| l)
+    }
+
+    /// The newer and older of the two positions to interpolate between at this point in time,
+    /// if we have positions from both before and after it.
+    fn tween_frames(&self, time: (u32, f32)) -> Option<[(u32, Vec2); 2]> {
+        let l = self.tween_index(time)?;
+        Some([self.pos_frames[l - 1], self.pos_frames[l]])
+    }
+
+    /// How far the entity was moving each tick as it passed the position at index `i`,
+    /// going by its Velocity if it was already moving like that, or the positions either side.
+    fn velocity_at(&self, i: usize) -> Vec2 {
+        let pfs = &self.pos_frames;
+        if let Some(&crate::Velocity { per_tick, since }) = crate::get(&self.comps) {
+            if pfs[i].0 >= since {
+                return per_tick;
+            }
+        }
+        let (t1, p1) = i.checked_sub(1).map_or(pfs[i], |newer| pfs[newer]);
+        let (t2, p2) = pfs.get(i + 1).copied().unwrap_or(pfs[i]);
+        if t1 > t2 {
+            (p1 - p2) / (t1 - t2) as f32
+        } else {
+            Vec2::zero()
+        }
+    }
+
+    /// How far the entity moved each tick between its two newest positions.
+    fn velocity(&self) -> Vec2 {
+        let [(t0, p0), (t1, p1)] = [self.pos_frames[0], self.pos_frames[1]];
+        if t0 > t1 {
+            (p0 - p1) / (t0 - t1) as f32
+        } else {
+            Vec2::zero()
+        }
+    }
+
+    /// Where the entity should be drawn at this point in time, see `Clock::tick`.
+    /// `complete` is the newest tick whose Snapshot arrived in full, see `Ents::complete_tick`.
+    pub fn pos_lerp(&self, time: (u32, f32), complete: u32) -> Vec2 {
+        let (jump, (tick, into)) = self.blend;
+        let since = time.0 as f32 - tick as f32 + time.1 - into;
+        self.raw_pos(time, complete) + jump * (1.0 - crate::smoothstep(since / BLEND_TICKS))
+    }
+
+    /// Where the entity should be drawn at this point in time, before smoothing over corrections.
+    fn raw_pos(&self, time: (u32, f32), complete: u32) -> Vec2 {
+        let pfs = &self.pos_frames;
+        if let Some(l) = self.tween_index(time) {
+            let [(t1, p1), (t2, p2)] = [pfs[l - 1], pfs[l]];
+            let expected = (t1 - t2) as f32;
+            let t = ((time.0 - t2) as f32 + time.1) / expected;
+            match crate::get::<crate::Interp>(&self.comps).copied().unwrap_or_default() {
+                crate::Interp::Linear => p2.lerp(p1, t),
+                crate::Interp::Hermite => {
+                    let (v2, v1) = (self.velocity_at(l), self.velocity_at(l - 1));
+                    crate::hermite(p2, v2 * expected, p1, v1 * expected, t)
+                }
+                crate::Interp::Angular { center } => crate::arc_lerp(center, p2, p1, t),
+            }
+        } else if self.starved(time) {
+            pfs[FRAMES_SAVED - 1].1
+        } else if self.extrapolating(time, complete) {
+            let ahead = (time.0 - pfs[0].0) as f32 + time.1;
+            pfs[0].1 + self.velocity() * ahead.min(MAX_EXTRAPOLATE_TICKS)
+        } else {
+            pfs[0].1
+        }
+    }
+
+    /// How many ticks of positions there are beyond the one being drawn at this point in time,
+    /// negative if we've run out and the entity is being extrapolated, or is parked.
+    pub fn buffered(&self, (tick, time): (u32, f32)) -> f32 {
+        self.pos_frames[0].0 as f32 - (tick as f32 + time)
+    }
+
+    /// Returns true if the entity is stuck at this point in time despite there being newer
+    /// positions for it, because there's nothing old enough to interpolate from.
+    /// Otherwise, if it isn't moving, it's just parked.
+    pub fn starved(&self, time: (u32, f32)) -> bool {
+        self.tween_frames(time).is_none() && self.pos_frames[0].0 > time.0
+    }
+
+    /// Returns true if the entity is being carried along past its newest position at this point
+    /// in time, because its positions are late. An entity missing from a complete Snapshot
+    /// hasn't moved, so once `complete` has passed its newest position it's left parked.
+    pub fn extrapolating(&self, time: (u32, f32), complete: u32) -> bool {
+        let newest = self.pos_frames[0].0;
+        newest >= complete && time.0 >= newest && self.velocity() != Vec2::zero()
+    }
+
+    /// Returns true if a Snapshot is missing between the positions being interpolated between
+    /// at this point in time, so that the entity is moving more smoothly than it really did.
+    pub fn gapped(&self, time: (u32, f32)) -> bool {
+        self.tween_frames(time).map_or(false, |[(t1, _), (t2, _)]| t1 - t2 > 1)
+    }
+}
+
+#[cfg(test)]
+fn moving_ent() -> Ent {
+    let mut ent = Ent::new(10, Vec2::zero(), vec![]);
+    ent.push_frame(11, Vec2::new(1.0, 0.0));
+    ent
+}
+
+#[test]
+fn extrapolation() {
+    let ent = moving_ent();
+    let x = |time, complete| ent.pos_lerp(time, complete).x();
+
+    assert_eq!(x((10, 0.5), 11), 0.5);
+    // tick 12 is late, so the entity carries on as it was
+    assert_eq!(x((12, 0.5), 11), 2.5);
+    assert!(ent.extrapolating((12, 0.5), 11));
+    // but not forever
+    assert_eq!(x((30, 0.0), 11), 1.0 + MAX_EXTRAPOLATE_TICKS);
+    // tick 12 arrived without it, so it stopped
+    assert_eq!(x((12, 0.5), 12), 1.0);
+    assert!(!ent.extrapolating((12, 0.5), 12));
+}
+
+#[test]
+fn interpolation_modes() {
+    use crate::{angle_to_vec, Comp, Interp};
+    // something revolving around `center` half a radian every tick, like a `Revolve`
+    let center = Vec2::new(1.0, 1.0);
+    let at = |tick: f32| center + angle_to_vec(tick * 0.5) * 2.0;
+
+    let off_by = |interp| {
+        let mut ent = Ent::new(10, at(10.0), vec![Comp::Interp(interp)]);
+        for tick in 11..=13 {
+            ent.push_frame(tick, at(tick as f32));
+        }
+        (ent.pos_lerp((11, 0.5), 13) - at(11.5)).length()
+    };
+
+    let linear = off_by(Interp::Linear);
+    assert!(linear > 0.05, "{}", linear);
+    let hermite = off_by(Interp::Hermite);
+    assert!(hermite < linear / 10.0, "{} vs {}", hermite, linear);
+    let angular = off_by(Interp::Angular { center });
+    assert!(angular < 1e-4, "{}", angular);
+}
+
+#[test]
+fn replicated_velocity() {
+    use crate::{Comp, Interp, Velocity};
+    // an island that was heading right and turned to head up on tick 13,
+    // where it was at on tick 12 having been lost along with that tick's Snapshot
+    let turned = Velocity { per_tick: Vec2::new(0.0, 1.0), since: 13 };
+    let really = Vec2::new(2.0, 0.0);
+    let island = |comps| {
+        let mut ent = Ent::new(10, Vec2::new(0.0, 0.0), comps);
+        ent.push_frame(11, Vec2::new(1.0, 0.0));
+        ent.push_frame(13, Vec2::new(2.0, 1.0));
+        ent
+    };
+    let guessed = island(vec![Comp::Interp(Interp::Hermite)]);
+    let known = island(vec![Comp::Interp(Interp::Hermite), Comp::Velocity(turned)]);
+
+    // the Velocity is believed from the tick it was moving like that,
+    assert_eq!(known.velocity_at(0), turned.per_tick);
+    assert_eq!(guessed.velocity_at(0), Vec2::new(0.5, 0.5));
+    // but before then the positions are all there is to go on
+    assert_eq!(known.velocity_at(1), guessed.velocity_at(1));
+
+    let off_by = |ent: &Ent| (ent.pos_lerp((12, 0.0), 13) - really).length();
+    assert!(off_by(&known) < off_by(&guessed), "{} vs {}", off_by(&known), off_by(&guessed));
+}
+
+#[test]
+fn corrections_are_blended() {
+    let mut ent = moving_ent();
+    let time = (12, 0.5);
+    assert_eq!(ent.pos_lerp(time, 11).x(), 2.5);
+
+    // it turns out the entity slowed down on tick 12
+    ent.correct(time, 11, 11, |ent| ent.push_frame(12, Vec2::new(1.5, 0.0)));
+    // so it starts off where it was drawn,
+    assert_eq!(ent.pos_lerp(time, 11).x(), 2.5);
+    // and moves smoothly to where it should be
+    let (halfway, raw) = (ent.pos_lerp((14, 0.5), 11).x(), ent.raw_pos((14, 0.5), 11).x());
+    assert!(halfway > raw && halfway < raw + 0.75);
+    assert_eq!(ent.pos_lerp((16, 0.5), 11), ent.raw_pos((16, 0.5), 11));
+
+    // positions that don't change where it's drawn leave the blend alone
+    let mut ent = moving_ent();
+    ent.correct((10, 0.5), 11, 11, |ent| ent.push_frame(12, Vec2::new(2.0, 0.0)));
+    assert_eq!(ent.blend.0, Vec2::zero());
+}
+
+/// How many of the Snapshots the server sends every tick have made it to us.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SnapshotStats {
+    /// Ticks whose Snapshots arrived in full
+    pub complete: u32,
+    /// Ticks skipped over between complete Snapshots, because some part of them never arrived
+    pub missed: u32,
+}
+
+/// Keeps track of which parts of the newest Snapshot have arrived,
+/// so that it can be acknowledged once all of them have.
+struct SnapshotReceipt {
+    tick: u32,
+    parts: Vec<bool>,
+    /// The newest tick whose Snapshot arrived in full
+    last_complete: Option<u32>,
+    stats: SnapshotStats,
+}
+impl SnapshotReceipt {
+    fn new(tick: u32) -> Self {
+        Self {
+            tick,
+            parts: Vec::with_capacity(8),
+            last_complete: None,
+            stats: SnapshotStats::default(),
+        }
+    }
+
+    /// Returns true if this part completes the Snapshot for its tick.
+    fn receive(&mut self, tick: u32, part: u16, parts: u16) -> bool {
+        if tick < self.tick {
+            return false;
+        }
+        if tick > self.tick {
+            self.tick = tick;
+            self.parts.clear();
+            self.parts.resize(parts as usize, false);
+        }
+
+        match self.parts.get_mut(part as usize) {
+            Some(got) if !*got => {
+                *got = true;
+                let complete = self.parts.iter().all(|&got| got);
+                if complete {
+                    if let Some(last) = self.last_complete {
+                        self.stats.missed += tick - last - 1;
+                    }
+                    self.last_complete = Some(tick);
+                    self.stats.complete += 1;
+                }
+                complete
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Estimates how unevenly complete Snapshots arrive, in ticks, the way RTP does.
+struct Jitter {
+    ticks: f32,
+    tick_ms: u32,
+    /// The tick of the newest complete Snapshot, when it arrived, and how long before that
+    /// Snapshots had last been polled for
+    last: Option<(u32, Instant, Duration)>,
+}
+impl Jitter {
+    fn new(tick_ms: u32) -> Self {
+        // a guess that keeps entities INTERP_DELAY_TICKS behind until there's a measurement
+        let ticks = (INTERP_DELAY_TICKS as f32 - MIN_DELAY_TICKS) / JITTER_MARGIN;
+        Self { ticks, tick_ms, last: None }
+    }
+
+    /// Measures how much sooner or later than expected the Snapshot for `tick` arrived.
+    ///
+    /// Snapshots are only noticed when they're polled for, once a frame, so `now` can be up to
+    /// `polled_every` after one really arrived. Being off by less than a frame could be down
+    /// to that alone, so only however much further off than that a Snapshot was is counted.
+    fn arrived(&mut self, tick: u32, now: Instant, polled_every: Duration) {
+        let in_ticks = |d: Duration| d.as_secs_f32() / (0.001 * self.tick_ms as f32);
+        if let Some((last_tick, last_at, last_polled_every)) = self.last {
+            let apart = in_ticks(now.saturating_duration_since(last_at));
+            let unsure = in_ticks(polled_every.max(last_polled_every));
+            let off = (apart - tick.saturating_sub(last_tick) as f32).abs();
+            self.ticks += ((off - unsure).max(0.0) - self.ticks) / 16.0;
+        }
+        self.last = Some((tick, now, polled_every));
+    }
+
+    /// How many ticks behind the newest positions entities should be drawn,
+    /// so that newer positions have usually arrived by the time they're needed.
+    fn delay(&self) -> f32 {
+        (MIN_DELAY_TICKS + JITTER_MARGIN * self.ticks).min(MAX_DELAY_TICKS)
+    }
+}
+
+#[test]
+fn interp_delay_adapts() {
+    let tick = Duration::from_millis(crate::SERVER_TICK_MS as _);
+    let start = Instant::now();
+    // Snapshots are noticed on the first frame after they arrive
+    let frame = Duration::from_secs_f64(1.0 / 60.0);
+    let noticed = |at: Duration| frame * (at.as_secs_f64() / frame.as_secs_f64()).ceil() as u32;
+    let arrive_every = |apart: &dyn Fn(u32) -> Duration| {
+        let mut jitter = Jitter::new(crate::SERVER_TICK_MS);
+        // a little after a frame, so that they aren't in step with the frames by chance
+        let mut at = Duration::from_millis(3);
+        for i in 0..200 {
+            at += apart(i);
+            jitter.arrived(i, start + noticed(at), frame);
+        }
+        jitter.delay()
+    };
+
+    // Snapshots arriving like clockwork can be drawn as soon as possible,
+    // even though how often frames are drawn makes them seem uneven
+    let steady = arrive_every(&|_| tick);
+    assert!((steady - MIN_DELAY_TICKS).abs() < 0.01, "{}", steady);
+    let uneven_frames = arrive_every(&|_| tick + Duration::from_millis(1));
+    assert!((uneven_frames - MIN_DELAY_TICKS).abs() < 0.01, "{}", uneven_frames);
+
+    // but if they come in pairs, there had better be a tick spare, less the frame
+    // they might just have been noticed late in
+    let paired = arrive_every(&|i| if i % 2 == 0 { Duration::from_millis(0) } else { tick * 2 });
+    let spare = 1.0 - frame.as_secs_f32() / tick.as_secs_f32();
+    assert!((paired - (MIN_DELAY_TICKS + JITTER_MARGIN * spare)).abs() < 0.1, "{}", paired);
+
+    // and there's only so long it's worth waiting
+    let erratic = arrive_every(&|i| if i % 2 == 0 { Duration::from_millis(0) } else { tick * 10 });
+    assert_eq!(erratic, MAX_DELAY_TICKS);
+}
+
+/// Once the Snapshot for `tick` is complete, anything that wasn't in it is still where it was,
+/// which is remembered as a position of its own so that an entity's positions stay a tick apart
+/// and it can't be drawn sweeping across every tick it spent at rest once it sets off again.
+/// Anything that was being carried along past its newest position is stopped.
+fn fill_in(ents: &mut fxhash::FxHashMap<u64, Ent>, tick: u32, time: (u32, f32), complete: u32) {
+    for ent in ents.values_mut().filter(|ent| ent.pos_frames[0].0 < tick) {
+        let pos = ent.pos_frames[0].1;
+        ent.correct(time, complete, tick, |ent| ent.push_frame(tick, pos));
+    }
+}
+
+#[test]
+fn resting_ents_set_off_smoothly() {
+    use crate::{Comp, Interp};
+    let mut ents = fxhash::FxHashMap::default();
+    ents.insert(1, Ent::new(0, Vec2::zero(), vec![Comp::Interp(Interp::Hermite)]));
+
+    // it stays put for a while, so the Snapshots leave it out
+    for tick in 1..100 {
+        fill_in(&mut ents, tick, (tick - 1, 0.0), tick - 1);
+    }
+    // then it sets off, and is drawn moving between those two ticks alone
+    let ent = ents.get_mut(&1).unwrap();
+    ent.push_frame(100, Vec2::new(1.0, 0.0));
+    let pos = ent.pos_lerp((99, 0.5), 100);
+    assert!((pos - Vec2::new(0.5, 0.0)).length() < 0.1, "{}", pos);
+    let pos = ent.pos_lerp((98, 0.5), 100);
+    assert!(pos.length() < 0.1, "{}", pos);
+}
+
+/// Everything in the World we've been told about, apart from our own island.
+pub struct Ents {
+    pub ents: fxhash::FxHashMap<u64, Ent>,
+    /// Which of our Worlds these are in, see `WorldJoin::epoch`
+    epoch: u32,
+    /// WorldEvents from a World whose WorldJoin hasn't arrived yet, see `World::travel`
+    early: Vec<WorldEvent>,
+    receipt: SnapshotReceipt,
+    jitter: Jitter,
+    /// When Snapshots were last polled for, see `Jitter::arrived`
+    polled: Instant,
+}
+impl Ents {
+    pub fn new(
+        mut islands: Vec<(u64, Vec2, Vec<Comp>)>,
+        epoch: u32,
+        tick: u32,
+        tick_ms: u32,
+    ) -> Self {
+        use {fxhash::FxBuildHasher, std::collections::HashMap};
+        let mut ents = HashMap::with_capacity_and_hasher(1000, FxBuildHasher::default());
+        ents.extend(islands.drain(..).map(|(i, p, c)| (i, Ent::new(tick, p, c))));
+        Self {
+            ents,
+            epoch,
+            early: vec![],
+            receipt: SnapshotReceipt::new(tick),
+            jitter: Jitter::new(tick_ms),
+            polled: Instant::now(),
+        }
+    }
+
+    /// `time` is where the Clock is, so that entities whose positions are corrected
+    /// can be moved smoothly from where they were being drawn.
+    /// Snapshots count as arriving when this is called, see `Jitter::arrived`.
+    pub fn poll_messages(&mut self, channels: &mut MessageChannels, time: (u32, f32)) {
+        use crate::{EntEvent, Snapshot, SnapshotAck};
+        let Self { ents, epoch, early, receipt, jitter, polled } = self;
+        let now = Instant::now();
+        let polled_every = now.saturating_duration_since(*polled);
+        *polled = now;
+
+        // WorldEvents arrive in order, so once one is early the rest are too
+        let received = std::iter::from_fn(|| channels.recv::<WorldEvent>());
+        for e in std::mem::take(early).into_iter().chain(received) {
+            log::trace!("{:?}", e);
+            if e.epoch != *epoch {
+                // anything from a World we've left is of no use
+                if e.epoch.wrapping_sub(*epoch) as i32 > 0 {
+                    early.push(e);
+                }
+                continue;
+            }
+            match e.event {
+                EntEvent::Spawn(id, tick, pos, comps) => {
+                    ents.insert(id, Ent::new(tick, pos, comps));
+                }
+                EntEvent::Update(id, comps) => {
+                    if let Some(ent) = ents.get_mut(&id) {
+                        ent.update(comps);
+                    }
+                }
+                EntEvent::Remove(id, kinds) => {
+                    if let Some(ent) = ents.get_mut(&id) {
+                        ent.remove(&kinds);
+                    }
+                }
+                EntEvent::Despawn(id) => {
+                    ents.remove(&id);
+                }
+            }
+        }
+        while let Some(Snapshot { epoch: snapshot_epoch, tick, part, parts, moves }) =
+            channels.recv()
+        {
+            if snapshot_epoch != *epoch {
+                continue;
+            }
+            let complete = receipt.last_complete.unwrap_or(0);
+            for (id, pos) in moves {
+                if let Some(ent) = ents.get_mut(&id) {
+                    ent.correct(time, complete, complete, |ent| ent.push_frame(tick, pos));
+                }
+            }
+
+            if receipt.receive(tick, part, parts) {
+                send_or_err(channels, SnapshotAck { epoch: *epoch, tick });
+                jitter.arrived(tick, now, polled_every);
+                fill_in(ents, tick, time, complete);
+            }
+        }
+    }
+
+    pub fn snapshot_stats(&self) -> SnapshotStats {
+        self.receipt.stats
+    }
+
+    /// The newest tick whose Snapshot arrived in full, see `Ent::pos_lerp`.
+    pub fn complete_tick(&self) -> u32 {
+        self.receipt.last_complete.unwrap_or(0)
+    }
+
+    /// How many ticks behind the newest positions entities should be drawn,
+    /// given how unevenly Snapshots have been arriving.
+    pub fn interp_delay(&self) -> f32 {
+        self.jitter.delay()
+    }
+
+    /// How unevenly Snapshots have been arriving, in ticks.
+    pub fn jitter(&self) -> f32 {
+        self.jitter.ticks
+    }
+
+    /// The tick of the newest Snapshot we've received any part of.
+    pub fn newest_tick(&self) -> u32 {
+        self.receipt.tick
+    }
+}
+
+/// Reminds the server we're still here, so that we aren't timed out.
+pub struct Heart(Instant);
+impl Default for Heart {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Heart {
+    pub fn new() -> Self {
+        Self(Instant::now() - Duration::from_secs(1))
+    }
+
+    /// How long ago the last Heartbeat was sent.
+    pub fn age(&self) -> Duration {
+        self.0.elapsed()
+    }
+
+    pub fn beat(&mut self, channel: &mut MessageChannels) {
+        if self.0.elapsed().as_secs_f32() > 0.2 {
+            self.0 = Instant::now();
+            crate::send(channel, Heartbeat);
+        }
+    }
+}
+
+/// The most a Clock runs fast or slow by while it catches up with the server.
+const MAX_DRIFT: f32 = 0.05;
+/// How many ticks off a Clock can be before it jumps straight to the right time,
+/// rather than drifting there.
+const SNAP_TICKS: f32 = 4.0;
+/// How much each sync is trusted over what the Clock already thought, as round trips vary.
+const SYNC_WEIGHT: f32 = 0.25;
+
+/// Keeps track of which tick to draw entities at, and how far into that tick we are.
+/// This is kept a little behind the server's tick, and kept there by `sync`.
+pub struct Clock {
+    tick: (u32, f32),
+    tick_ms: u32,
+    last_tick_taken: Instant,
+    /// How many ticks the Clock has yet to gain, or lose if negative, to be in step
+    correction: f32,
+    /// How many ticks behind the newest positions from the server the Clock aims to be
+    pub delay: f32,
+}
+impl Clock {
+    /// A Clock for a World the server says is on `tick`.
+    pub fn new(tick: u32, tick_ms: u32) -> Self {
+        Self {
+            tick: (tick.saturating_sub(INTERP_DELAY_TICKS), 0.0),
+            tick_ms,
+            last_tick_taken: Instant::now(),
+            correction: 0.0,
+            delay: INTERP_DELAY_TICKS as f32,
+        }
+    }
+
+    pub fn tick(&mut self) -> (u32, f32) {
+        self.tick_at(Instant::now())
+    }
+
+    fn tick_at(&mut self, now: Instant) -> (u32, f32) {
+        let Self { tick: (tick, left), tick_ms, last_tick_taken, correction, .. } = self;
+        let elapsed = now.saturating_duration_since(*last_tick_taken).as_secs_f32()
+            / (0.001 * *tick_ms as f32);
+        *last_tick_taken = now;
+
+        // run a little fast or slow until the correction has been made
+        let drift = correction.clamp(-MAX_DRIFT * elapsed, MAX_DRIFT * elapsed);
+        *correction -= drift;
+        *left += elapsed + drift;
+
+        let whole = left.floor();
+        *tick += whole as u32;
+        *left -= whole;
+
+        (*tick, *left)
+    }
+
+    /// Brings the Clock into step with the server's, given the tick from a TimeReply
+    /// that has just arrived.
+    ///
+    /// Only that tick matters, not how long the reply took. The server has moved on since,
+    /// but Snapshots take just as long to get here, so the newest positions we have are
+    /// from about the tick in the reply, and the Clock aims to be `delay` behind those.
+    pub fn sync(&mut self, server_tick: u32) {
+        self.sync_at(server_tick, Instant::now())
+    }
+
+    fn sync_at(&mut self, server_tick: u32, now: Instant) {
+        let (tick, left) = self.tick_at(now);
+        let target = server_tick as f64 - self.delay as f64;
+        let error = (target - (tick as f64 + left as f64)) as f32;
+
+        if error.abs() > SNAP_TICKS {
+            let target = target.max(0.0);
+            self.tick = (target.floor() as u32, target.fract() as f32);
+            self.correction = 0.0;
+        } else {
+            self.correction += (error - self.correction) * SYNC_WEIGHT;
+        }
+    }
+
+    /// How many ticks the Clock has yet to gain, or lose if negative, to be in step.
+    pub fn correction(&self) -> f32 {
+        self.correction
+    }
+}
+
+#[test]
+fn clock() {
+    let tick = Duration::from_millis(crate::SERVER_TICK_MS as _);
+    let mut one_tick_clock = Clock::new(100, crate::SERVER_TICK_MS);
+    let mut clock = Clock::new(100, crate::SERVER_TICK_MS);
+    let start = clock.last_tick_taken;
+    one_tick_clock.last_tick_taken = start;
+
+    for i in 1..=100 {
+        clock.tick_at(start + tick * i);
+    }
+
+    let reading = |(tick, left): (u32, f32)| tick as f32 + left;
+    let end = start + tick * 100;
+    assert!((reading(clock.tick_at(end)) - reading(one_tick_clock.tick_at(end))).abs() < 0.001);
+    assert!((reading(clock.tick_at(end)) - 198.0).abs() < 0.001);
+}
+
+#[test]
+fn clock_sync() {
+    let tick = Duration::from_millis(crate::SERVER_TICK_MS as _);
+    let reading = |(tick, left): (u32, f32)| tick as f64 + left as f64;
+    // replies take two ticks to arrive, so the tick in them is two ticks old,
+    // just like the newest positions
+
+    // the server was on tick 103 when the Clock started, so the Clock should have started
+    // at 99 to stay two ticks behind the newest positions, not 98
+    let mut clock = Clock::new(100, crate::SERVER_TICK_MS);
+    let start = clock.last_tick_taken;
+    let mut last = reading(clock.tick);
+    for i in 1..=400 {
+        let now = start + tick * i;
+        if i % 10 == 0 {
+            clock.sync_at(103 + i - 2, now);
+        }
+        let time = reading(clock.tick_at(now));
+        // never faster or slower than it's allowed to drift
+        assert!((time - last - 1.0).abs() <= MAX_DRIFT as f64 + 0.001, "{} then {}", last, time);
+        last = time;
+    }
+    // drawing two ticks behind the newest positions, which are two ticks old
+    let target = (103 + 400 - 2 - 2) as f64;
+    assert!((last - target).abs() < 0.05, "{} isn't {}", last, target);
+
+    // too far off to drift there, it jumps
+    let mut clock = Clock::new(100, crate::SERVER_TICK_MS);
+    let start = clock.last_tick_taken;
+    clock.sync_at(150, start);
+    assert!((reading(clock.tick_at(start)) - 148.0).abs() < 0.001);
+}
+
+/// Returns a MessageChannels corresponding to a UDP socket that only accepts messages from,
+/// and sends messages to, a single address.
+pub fn direct_socket(
+    my_addr: &str,
+    remote_addr: &str,
+    pool_size: usize,
+    conditions: LinkConditions,
+) -> MessageChannels {
+    use crate::{
+        link::{random_seed, Inbox},
+        net::{acquire_max, channel_with_multiplexer, send_outgoing_to_socket, SimpleBufferPool},
+    };
+    use turbulence::{BufferPacketPool, Packet};
+
+    let pool = BufferPacketPool::new(SimpleBufferPool(pool_size));
+    let (channel, multiplexer) = channel_with_multiplexer(pool.clone());
+
+    let socket = smol::block_on(async {
+        let s = smol::net::UdpSocket::bind(my_addr).await.expect("couldn't bind to address");
+        s.connect(remote_addr).await.expect("connect function failed");
+        s
+    });
+
+    let (incoming, outgoing) = multiplexer.start();
+    let remote_addr = socket.peer_addr().expect("connected socket has no peer");
+    send_outgoing_to_socket(outgoing, socket.clone(), remote_addr, conditions);
+    let mut inbox = Inbox::new(incoming, pool.clone(), conditions, random_seed());
+
+    smol::spawn(async move {
+        loop {
+            let mut packet = acquire_max(&pool);
+            match socket.recv(&mut packet).await {
+                Ok(len) => {
+                    packet.truncate(len);
+                    crate::net::count_received(&packet);
+                    inbox.deliver(packet);
+                }
+                Err(e) => log::error!("couldn't recieve packet from UDP socket: {}", e),
+            };
+        }
+    })
+    .detach();
+
+    channel
+}
+
+/// Introduces us to the server at `remote_addr`, asking to be known as `name`,
+/// and to be made an op if we have an `op_token`.
+/// Follow up with `poll_join` until we're let into a World.
+/// Packets go both ways across a simulated link with these conditions, unless they're perfect.
+pub fn connect(
+    remote_addr: &str,
+    name: &str,
+    op_token: Option<&str>,
+    conditions: LinkConditions,
+) -> MessageChannels {
+    let mut channel = direct_socket(crate::CLIENT, remote_addr, 1024, conditions);
+    introduce(&mut channel, name, op_token);
+    channel
+}
+
+/// Introduces us to whichever server is on the other end of `channel`, see `connect`.
+pub fn introduce(channel: &mut MessageChannels, name: &str, op_token: Option<&str>) {
+    let op_token = op_token.map(str::to_string);
+    send_or_err(channel, crate::Handshake::ours());
+    send_or_err(channel, crate::Introduction { name: name.to_string(), op_token });
+    channel.flush::<crate::Handshake>();
+    channel.flush::<crate::Introduction>();
+}
+
+/// Returns the first World we've been put in, or why the server turned us away,
+/// once either has happened. Call this every frame until it does.
+pub fn poll_join(
+    channel: &mut MessageChannels,
+    heart: &mut Heart,
+) -> Option<Result<WorldJoin, String>> {
+    if let Some(HandshakeReply::Rejected(reason)) = channel.recv() {
+        return Some(Err(reason));
+    }
+    if let Some(join) = channel.recv() {
+        return Some(Ok(join));
+    }
+
+    heart.beat(channel);
+    channel.flush::<Heartbeat>();
+    None
+}
+
+/// Everything that belongs to the World the server has put us in.
+pub struct World {
+    pub name: String,
+    pub ents: Ents,
+    pub your_island: u64,
+    /// The components our island had when we arrived
+    pub your_comps: Vec<Comp>,
+    pub you: Predictor,
+    pub clock: Clock,
+    /// The newest result of `Clock::tick`
+    pub time: (u32, f32),
+}
+impl World {
+    /// `seq` is that of the last Input sent in the previous world, if there was one.
+    pub fn arrive(join: WorldJoin, seq: u32) -> Self {
+        let WorldJoin { epoch, your_island, world_name, islands, tick, tick_ms } = join;
+
+        let mut ents = Ents::new(islands, epoch, tick, tick_ms);
+        let you = ents.ents.remove(&your_island).expect("WorldJoin is missing your island");
+
+        Self {
+            name: world_name,
+            ents,
+            your_island,
+            your_comps: you.comps,
+            you: Predictor::new(you.pos_frames[0].1, tick, tick_ms, seq),
+            clock: Clock::new(tick, tick_ms),
+            time: (tick, 0.0),
+        }
+    }
+
+    /// Moves us into another World the server has put us in,
+    /// keeping anything about its entities that arrived before its WorldJoin did.
+    pub fn travel(&mut self, join: WorldJoin) {
+        let early = std::mem::take(&mut self.ents.early);
+        *self = Self::arrive(join, self.you.seq());
+        self.ents.early = early;
+    }
+}
+
+/// What happened while a Client was keeping up with the server.
+pub enum Update {
+    Stayed,
+    /// The server has put us in a new World, which is now `Client::world`
+    Arrived,
+    /// The server has sent us away, for this reason
+    Disconnected(String),
+}
+
+/// How often the Client asks the server what tick it is, to keep its Clock in step.
+const SYNC_EVERY: Duration = Duration::from_millis(500);
+
+/// The network half of a client, which keeps up with the server without drawing anything.
+/// Anything the Client doesn't handle itself, like Chat, is left in `channel` for its owner.
+pub struct Client {
+    pub channel: MessageChannels,
+    pub heart: Heart,
+    pub world: World,
+    /// How long a round trip to the server took, as of the newest Ping
+    pub rtt: Option<Duration>,
+    /// The id of the newest TimeRequest, and when it was sent
+    sync: (u32, Instant),
+    /// Whether the newest TimeRequest has been answered, or should no longer be
+    synced: bool,
+}
+impl Client {
+    pub fn new(channel: MessageChannels, heart: Heart, join: WorldJoin) -> Self {
+        Self {
+            channel,
+            heart,
+            world: World::arrive(join, 0),
+            rtt: None,
+            sync: (0, Instant::now() - SYNC_EVERY),
+            synced: true,
+        }
+    }
+
+    /// Catches up on everything the server has said, steering our island in `dir`.
+    /// Nothing is sent until `channel` is flushed, which should be done afterwards.
+    pub fn update(&mut self, dir: Vec2) -> Update {
+        let Self { channel, heart, world, rtt, sync: (sync_id, sync_sent), synced } = self;
+
+        if let Some(Disconnect { reason }) = channel.recv() {
+            return Update::Disconnected(reason);
+        }
+        let mut update = Update::Stayed;
+        if let Some(join) = channel.recv() {
+            world.travel(join);
+            update = Update::Arrived;
+            // the new World is on a different tick, so ask again straight away
+            *synced = true;
+            *sync_sent = Instant::now() - SYNC_EVERY;
+        }
+
+        while let Some(Ping { id, last_rtt_ms }) = channel.recv() {
+            *rtt = last_rtt_ms.map(|ms| Duration::from_millis(ms as u64)).or(*rtt);
+            send_or_err(channel, Pong(id));
+        }
+
+        let World { ents, you, clock, time, .. } = world;
+        while let Some(TimeReply { id, tick }) = channel.recv() {
+            if id == *sync_id && !*synced {
+                clock.sync(tick);
+                *synced = true;
+            }
+        }
+        if sync_sent.elapsed() >= SYNC_EVERY {
+            *sync_id += 1;
+            *sync_sent = Instant::now();
+            *synced = false;
+            send_or_err(channel, TimeRequest(*sync_id));
+        }
+
+        *time = clock.tick();
+        heart.beat(channel);
+        you.reconcile(channel);
+        you.steer(channel, time.0, dir);
+        ents.poll_messages(channel, *time);
+        clock.delay = ents.interp_delay();
+        update
+    }
+
+    /// Tells the server we're leaving, and why.
+    pub fn leave(&mut self, reason: &str) {
+        let Self { channel, .. } = self;
+        send_or_err(channel, Disconnect { reason: reason.to_string() });
+        channel.flush::<Disconnect>();
+    }
+}
@@ -1,11 +1,21 @@
 use serde::{Deserialize, Serialize};
 
 pub mod net;
-pub use net::{messages::*, send_or_err, CLIENT, SERVER};
+pub use net::{
+    messages::*, send, send_or_err, traffic, EntEvent, Traffic, BUILD_HASH, CLIENT, MAX_PAST_CHATS,
+    PROTOCOL_VERSION, SERVER, SNAPSHOT_MOVES_PER_PART,
+};
 
 mod math;
 pub use math::*;
 
+mod replicate;
+pub use replicate::*;
+
+pub mod headless;
+pub mod link;
+pub mod predict;
+
 #[macro_export]
 macro_rules! or_err {
     ( $r:expr ) => {
@@ -20,9 +30,60 @@ macro_rules! or_err {
     };
 }
 
+/// The default length of a server tick, servers may be configured to use another.
 pub const SERVER_TICK_MS: u32 = 50;
 
-#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+/// How far a player's island can move in a second.
+pub const ISLAND_SPEED: f32 = 2.0;
+
+/// The velocity of an island whose player would like to move in `dir`.
+/// Directions longer than one are clamped, so that nobody goes faster than `ISLAND_SPEED`.
+pub fn island_velocity(dir: glam::Vec2) -> glam::Vec2 {
+    let len = dir.length();
+    if !len.is_finite() {
+        glam::Vec2::zero()
+    } else if len > 1.0 {
+        dir / len * ISLAND_SPEED
+    } else {
+        dir * ISLAND_SPEED
+    }
+}
+
+/// What a client goes by, chosen when they connect and unique on their server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Name(pub String);
+impl Name {
+    pub const MAX_LEN: usize = 16;
+
+    /// Returns why this can't be used as a Name, or None if it can.
+    pub fn problem(name: &str) -> Option<String> {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+        if name.is_empty() {
+            Some("names can't be empty".to_string())
+        } else if name.chars().count() > Self::MAX_LEN {
+            Some(format!("names can be at most {} characters long", Self::MAX_LEN))
+        } else if !name.chars().all(allowed) {
+            Some("names may only contain letters, numbers, _ and -".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Who a Chat is meant for.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatScope {
+    /// Everyone in the same World as the sender
+    World,
+    /// Everyone on the server
+    Global,
+    /// Only the client with this name, and whoever sent it
+    Whisper(String),
+    /// The server itself, rather than another client
+    System,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum Art {
     Island,
     Vase,
@@ -35,3 +96,28 @@ impl Art {
         }
     }
 }
+
+/// How clients fill in an entity's movement between the positions they're sent for it.
+/// Entities without one are moved in straight lines.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Interp {
+    /// In a straight line from each position to the next
+    Linear,
+    /// Along a cubic Hermite curve, passing through each position at the entity's Velocity,
+    /// or at the velocity the positions either side of it suggest if that's all there is to go on
+    Hermite,
+    /// Around `center`, for things that go in circles
+    Angular { center: glam::Vec2 },
+}
+impl Default for Interp {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+
+/// How far an entity moves each tick, and the first tick whose position it was moving like this at.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Velocity {
+    pub per_tick: glam::Vec2,
+    pub since: u32,
+}
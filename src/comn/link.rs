@@ -0,0 +1,326 @@
+//! Simulated network links, for seeing how things hold up over a bad connection
+//! and for connecting clients to servers in the same process without any sockets.
+use crate::net::{channel_with_multiplexer, GlobalSmolRuntime, SimpleBufferPool};
+use serde::{Deserialize, Serialize};
+use smol::stream::{Stream, StreamExt};
+use std::time::{Duration, Instant};
+use turbulence::{
+    BufferPacket, BufferPacketPool, IncomingMultiplexedPackets, MessageChannels, Runtime,
+};
+
+/// How a simulated link mistreats the packets sent across it.
+/// The default is a perfect link, which delivers everything instantly and in order.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct LinkConditions {
+    /// How long every packet takes to arrive
+    pub latency_ms: u32,
+    /// Each packet takes up to this much longer to arrive than `latency_ms`, picked at random
+    pub jitter_ms: u32,
+    /// The chance of any packet never arriving, from 0.0 to 1.0
+    pub loss: f32,
+    /// The chance of any packet arriving twice, from 0.0 to 1.0
+    pub duplicate: f32,
+    /// The chance of any packet being held back long enough
+    /// that it probably arrives after some sent after it, from 0.0 to 1.0
+    pub reorder: f32,
+    /// How many bytes can be sent each second, or 0 for as many as you like.
+    /// Packets that would have to wait in line for more than a second are dropped.
+    pub bandwidth: u32,
+}
+impl LinkConditions {
+    /// The command line options for each condition, see `LinkConditions::set`.
+    pub const FLAGS: [&'static str; 6] =
+        ["--latency", "--jitter", "--loss", "--duplicate", "--reorder", "--bandwidth"];
+
+    /// Returns true if packets would arrive untouched, so there's no need to simulate anything.
+    pub fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns why these conditions don't make sense, if they don't.
+    pub fn problem(&self) -> Option<String> {
+        let chances =
+            [("loss", self.loss), ("duplicate", self.duplicate), ("reorder", self.reorder)];
+        chances
+            .iter()
+            .find(|(_, chance)| !(0.0..=1.0).contains(chance))
+            .map(|(name, chance)| format!("{} must be from 0.0 to 1.0, not {}", name, chance))
+    }
+
+    /// Sets a condition from one of the `FLAGS` and the value given for it on the command line.
+    pub fn set(&mut self, flag: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String>
+        where
+            T::Err: std::fmt::Display,
+        {
+            value.parse().map_err(|e| format!("invalid value {:?} for {}: {}", value, flag, e))
+        }
+
+        match flag {
+            "--latency" => self.latency_ms = parse(flag, value)?,
+            "--jitter" => self.jitter_ms = parse(flag, value)?,
+            "--loss" => self.loss = parse(flag, value)?,
+            "--duplicate" => self.duplicate = parse(flag, value)?,
+            "--reorder" => self.reorder = parse(flag, value)?,
+            "--bandwidth" => self.bandwidth = parse(flag, value)?,
+            _ => return Err(format!("{} isn't a link condition", flag)),
+        }
+        self.problem().map_or(Ok(()), Err)
+    }
+
+    /// How long a packet sent now takes to arrive, not counting any time spent waiting in line.
+    fn delay(&self, dice: &mut Dice) -> Duration {
+        let jitter = Duration::from_millis(self.jitter_ms as u64);
+        let mut delay = Duration::from_millis(self.latency_ms as u64) + jitter.mul_f32(dice.roll());
+        if dice.roll() < self.reorder {
+            delay += jitter + Duration::from_millis(10);
+        }
+        delay
+    }
+}
+
+/// A tiny xorshift generator, so that the same seed always mistreats packets the same way.
+struct Dice(u64);
+impl Dice {
+    fn new(seed: u64) -> Self {
+        // xorshift gets stuck on zero
+        Self(seed.max(1))
+    }
+
+    /// A number from 0.0 up to but not including 1.0
+    fn roll(&mut self) -> f32 {
+        let Self(x) = self;
+        *x ^= *x << 13;
+        *x ^= *x >> 7;
+        *x ^= *x << 17;
+        (*x >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// A seed for `simulate` that's different every time, for when nobody needs to reproduce a run.
+pub fn random_seed() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_nanos() as u64)
+}
+
+/// Spawns a task which passes along each of the `packets` once they've made it across a link
+/// with these conditions, returning the packets that arrive.
+pub fn simulate(
+    mut packets: impl Stream<Item = Vec<u8>> + Unpin + Send + 'static,
+    conditions: LinkConditions,
+    seed: u64,
+) -> impl Stream<Item = Vec<u8>> + Unpin + Send + 'static {
+    let (arrived_tx, arrived_rx) = smol::channel::unbounded();
+    GlobalSmolRuntime.spawn(async move {
+        let mut dice = Dice::new(seed);
+        // sorted by when they arrive, soonest first
+        let mut in_flight: Vec<(Instant, Vec<u8>)> = Vec::with_capacity(32);
+        // when the last packet waiting in line will have been sent, if there's a bandwidth cap
+        let mut line_clear = Instant::now();
+        // packets still in flight are delivered even after whoever sent them is gone
+        let mut open = true;
+
+        while open || !in_flight.is_empty() {
+            let soonest = in_flight.first().map(|(at, _)| *at);
+            let sent = async {
+                if open {
+                    Some(packets.next().await)
+                } else {
+                    smol::future::pending().await
+                }
+            };
+            let due = async {
+                match soonest {
+                    Some(at) => smol::Timer::at(at).await,
+                    None => smol::future::pending().await,
+                };
+                None
+            };
+
+            match smol::future::or(sent, due).await {
+                Some(Some(packet)) => {
+                    let now = Instant::now();
+                    let mut departs = now;
+                    if conditions.bandwidth > 0 {
+                        departs = line_clear.max(now);
+                        if departs - now > Duration::from_secs(1) {
+                            continue;
+                        }
+                        let secs = packet.len() as f32 / conditions.bandwidth as f32;
+                        line_clear = departs + Duration::from_secs_f32(secs);
+                    }
+                    if dice.roll() < conditions.loss {
+                        continue;
+                    }
+
+                    let copies = if dice.roll() < conditions.duplicate { 2 } else { 1 };
+                    for _ in 0..copies {
+                        let at = departs + conditions.delay(&mut dice);
+                        let i = in_flight.partition_point(|(other, _)| *other <= at);
+                        in_flight.insert(i, (at, packet.clone()));
+                    }
+                }
+                Some(None) => open = false,
+                None => {
+                    let now = Instant::now();
+                    let arrived = in_flight.partition_point(|(at, _)| *at <= now);
+                    for (_, packet) in in_flight.drain(..arrived) {
+                        if arrived_tx.send(packet).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+    arrived_rx
+}
+
+/// Where packets received from a socket go to be read by a MessageChannels,
+/// by way of a simulated link unless the conditions are perfect.
+pub enum Inbox {
+    Direct(IncomingMultiplexedPackets<BufferPacket<Box<[u8]>>>),
+    Simulated(smol::channel::Sender<Vec<u8>>),
+}
+impl Inbox {
+    pub fn new(
+        mut incoming: IncomingMultiplexedPackets<BufferPacket<Box<[u8]>>>,
+        pool: BufferPacketPool<SimpleBufferPool>,
+        conditions: LinkConditions,
+        seed: u64,
+    ) -> Self {
+        if conditions.is_perfect() {
+            return Inbox::Direct(incoming);
+        }
+
+        let (received_tx, received_rx) = smol::channel::unbounded();
+        let mut arrived = simulate(received_rx, conditions, seed);
+        GlobalSmolRuntime.spawn(async move {
+            while let Some(bytes) = arrived.next().await {
+                if !deliver(&mut incoming, crate::net::packet_from(&pool, &bytes)) {
+                    break;
+                }
+            }
+        });
+        Inbox::Simulated(received_tx)
+    }
+
+    /// Returns false if the MessageChannels these packets were for is gone.
+    pub fn deliver(&mut self, packet: BufferPacket<Box<[u8]>>) -> bool {
+        match self {
+            Inbox::Direct(incoming) => deliver(incoming, packet),
+            Inbox::Simulated(received) => received.try_send(packet.to_vec()).is_ok(),
+        }
+    }
+}
+
+/// Returns false if the MessageChannels these packets were for is gone.
+fn deliver(
+    incoming: &mut IncomingMultiplexedPackets<BufferPacket<Box<[u8]>>>,
+    packet: BufferPacket<Box<[u8]>>,
+) -> bool {
+    use turbulence::packet_multiplexer::{IncomingError::*, IncomingTrySendError::*};
+    match incoming.try_send(packet) {
+        Ok(()) => true,
+        Err(Error(ChannelReceiverDropped)) => false,
+        Err(e) => {
+            log::error!("couldn't send packet: {}", e);
+            true
+        }
+    }
+}
+
+/// Connects two MessageChannels to each other without any sockets, as though they were on
+/// either end of a network link with these conditions. Both directions get the same conditions,
+/// but their packets are mistreated independently.
+pub fn loopback(
+    conditions: LinkConditions,
+    seed: u64,
+    pool_size: usize,
+) -> (MessageChannels, MessageChannels) {
+    let pool = BufferPacketPool::new(SimpleBufferPool(pool_size));
+    let (a, a_multiplexer) = channel_with_multiplexer(pool.clone());
+    let (b, b_multiplexer) = channel_with_multiplexer(pool.clone());
+    let (a_incoming, a_outgoing) = a_multiplexer.start();
+    let (b_incoming, b_outgoing) = b_multiplexer.start();
+
+    for (outgoing, mut incoming, seed) in
+        vec![(a_outgoing, b_incoming, seed), (b_outgoing, a_incoming, !seed)]
+    {
+        let mut arrived = simulate(outgoing.map(|p| p.to_vec()), conditions, seed);
+        let pool = pool.clone();
+        GlobalSmolRuntime.spawn(async move {
+            while let Some(bytes) = arrived.next().await {
+                // the other end is gone, so there's nobody left to deliver to
+                if !deliver(&mut incoming, crate::net::packet_from(&pool, &bytes)) {
+                    break;
+                }
+            }
+        });
+    }
+
+    (a, b)
+}
+
+#[test]
+fn dice_rolls_are_fair() {
+    let mut dice = Dice::new(1337);
+    let rolls: Vec<f32> = (0..10_000).map(|_| dice.roll()).collect();
+
+    assert!(rolls.iter().all(|&r| (0.0..1.0).contains(&r)));
+    let mean = rolls.iter().sum::<f32>() / rolls.len() as f32;
+    assert!((mean - 0.5).abs() < 0.02, "mean roll was {}", mean);
+}
+
+#[test]
+fn simulated_link() {
+    let packets = || smol::stream::iter((0..1000u32).map(|i| i.to_le_bytes().to_vec()));
+    let numbered = |arrived: Vec<Vec<u8>>| -> Vec<u32> {
+        arrived.iter().map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]])).collect()
+    };
+
+    let conditions = LinkConditions {
+        latency_ms: 30,
+        jitter_ms: 10,
+        loss: 0.25,
+        duplicate: 0.1,
+        reorder: 0.25,
+        ..Default::default()
+    };
+    let start = Instant::now();
+    let arrived = numbered(smol::block_on(simulate(packets(), conditions, 7).collect()));
+
+    // everything should take at least the latency to arrive
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    let mut unique = arrived.clone();
+    unique.sort_unstable();
+    unique.dedup();
+    let lost = 1000 - unique.len();
+    assert!((150..350).contains(&lost), "lost {} of 1000 packets", lost);
+    let duplicated = arrived.len() - unique.len();
+    assert!((25..150).contains(&duplicated), "duplicated {} packets", duplicated);
+    assert!(arrived.windows(2).any(|w| w[0] > w[1]), "nothing was reordered");
+
+    // a perfect link leaves everything as it was
+    let arrived =
+        numbered(smol::block_on(simulate(packets(), LinkConditions::default(), 7).collect()));
+    assert_eq!(arrived, (0..1000).collect::<Vec<_>>());
+}
+
+#[test]
+fn bandwidth_cap() {
+    // 100 packets of 100 bytes at 20000 bytes a second take half a second to send
+    let packets = smol::stream::iter((0..100).map(|_| vec![0; 100]));
+    let conditions = LinkConditions { bandwidth: 20_000, ..Default::default() };
+
+    let start = Instant::now();
+    let arrived: Vec<Vec<u8>> = smol::block_on(simulate(packets, conditions, 7).collect());
+    assert_eq!(arrived.len(), 100);
+    assert!(start.elapsed() >= Duration::from_millis(450), "took {:?}", start.elapsed());
+
+    // any more than a second's worth waiting in line and the rest are dropped
+    let packets = smol::stream::iter((0..300).map(|_| vec![0; 100]));
+    let arrived: Vec<Vec<u8>> = smol::block_on(simulate(packets, conditions, 7).collect());
+    assert!(arrived.len() < 250, "{} arrived", arrived.len());
+}
@@ -84,7 +84,7 @@ pub fn slerp(q1: Vec2, q0: Vec2, t: f32) -> Vec2 {
 
     let mut ts = [0.0; 8];
     let mut ds = [0.0; 8];
-    for i in (0..7).rev() {
+    for i in (0..8).rev() {
         ts[i] = (U[i] * t_pow2 - V[i]) * xm1;
         ds[i] = (U[i] * d_pow2 - V[i]) * xm1;
     }
@@ -119,3 +119,44 @@ pub fn slerp(q1: Vec2, q0: Vec2, t: f32) -> Vec2 {
 
     q0 * f0 + q1 * f1
 }
+
+/// Technically a Quadratic Bezier Curve.
+/// that won't stop me from calling it a "three_lerp" or a thlerp for short :)
+pub fn thlerp(p0: Vec2, p1: Vec2, p2: Vec2, t: f32) -> Vec2 {
+    p0.lerp(p1, t).lerp(p1.lerp(p2, t), t)
+}
+
+/// A cubic Hermite curve from `p0` to `p1`, leaving with velocity `v0` and arriving with `v1`,
+/// where a velocity is how far it would carry you over the whole curve.
+pub fn hermite(p0: Vec2, v0: Vec2, p1: Vec2, v1: Vec2, t: f32) -> Vec2 {
+    // the same curve as a cubic Bezier, which is just two thlerps lerped together
+    let (c0, c1) = (p0 + v0 / 3.0, p1 - v1 / 3.0);
+    thlerp(p0, c0, c1, t).lerp(thlerp(c0, c1, p1, t), t)
+}
+
+/// Goes around `center` from `p0` to `p1`, rather than cutting across in a straight line.
+pub fn arc_lerp(center: Vec2, p0: Vec2, p1: Vec2, t: f32) -> Vec2 {
+    let (r0, r1) = (p0 - center, p1 - center);
+    let (l0, l1) = (r0.length(), r1.length());
+    if l0 <= f32::EPSILON || l1 <= f32::EPSILON {
+        return p0.lerp(p1, t);
+    }
+    center + slerp(r0 / l0, r1 / l1, t) * lerp(l0, l1, t)
+}
+
+#[test]
+fn curves_end_where_they_should() {
+    let (p0, p1) = (vec2(1.0, 0.0), vec2(0.0, 1.0));
+    let close = |a: Vec2, b: Vec2| (a - b).length() < 1e-4;
+
+    for &t in &[0.0, 1.0] {
+        let end = p0.lerp(p1, t);
+        assert!(close(thlerp(p0, vec2(5.0, 5.0), p1, t), end));
+        assert!(close(hermite(p0, vec2(0.0, 3.0), p1, vec2(-3.0, 0.0), t), end));
+        assert!(close(arc_lerp(Vec2::zero(), p0, p1, t), end));
+    }
+    // halfway around the arc is still on the circle, halfway across it isn't
+    assert!(close(arc_lerp(Vec2::zero(), p0, p1, 0.5), Vec2::one().normalize()));
+    // leaving and arriving at the speed it takes to get there, a Hermite curve is a straight line
+    assert!(close(hermite(p0, p1 - p0, p1, p1 - p0, 0.25), p0.lerp(p1, 0.25)));
+}
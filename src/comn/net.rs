@@ -1,8 +1,9 @@
-use super::{Chat, Heartbeat};
+use super::{Chat, ChatScope, Heartbeat};
 use smol::stream::StreamExt;
 use std::{
     future::Future,
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
     time::{Duration, Instant},
 };
@@ -16,12 +17,119 @@ use turbulence::{
 pub const CLIENT: &str = "127.0.0.1:0";
 pub const SERVER: &str = "127.0.0.1:1337";
 
-pub fn send_or_err<M: ChannelMessage + std::fmt::Debug>(channels: &mut MessageChannels, m: M) {
-    if let Some(rejected) = channels.send(m) {
+/// A (u64, Vec2) is 16 bytes, so this many keeps each Snapshot part comfortably
+/// under 1024 bytes, even after the rest of the Snapshot and the packet headers.
+pub const SNAPSHOT_MOVES_PER_PART: usize = 60;
+
+/// How many PastChats can be sent at once, a World's chat backlog may be no longer than this.
+pub const MAX_PAST_CHATS: usize = 32;
+
+/// Bump this whenever the layout of any message changes.
+///
+/// `Handshake` and `HandshakeReply` must remain the first two channels and keep their layout,
+/// so that mismatched builds can always be told why they're being turned away.
+pub const PROTOCOL_VERSION: u32 = 18;
+/// The git revision this binary was built from, see build.rs
+pub const BUILD_HASH: &str = env!("BUILD_HASH");
+
+pub fn send_or_err<M: ChannelMessage + std::fmt::Debug + 'static>(
+    channels: &mut MessageChannels,
+    m: M,
+) {
+    if let Some(rejected) = send(channels, m) {
         log::error!("channel rejected message: {:#?}", rejected);
     }
 }
 
+/// Sends a message, counting it in the `traffic`, and hands it back if the channel was full.
+/// Only for messages that it's fine to give up on or try again later, see `send_or_err`.
+pub fn send<M: ChannelMessage + 'static>(channels: &mut MessageChannels, m: M) -> Option<M> {
+    let rejected = channels.send(m);
+    if let Some(counters) = channel_of::<M>().map(|channel| &TRAFFIC[channel]) {
+        let counter = if rejected.is_some() { &counters.rejected } else { &counters.messages };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+    rejected
+}
+
+/// Counts what this process has sent over one channel, see `traffic`.
+struct ChannelCounters {
+    messages: AtomicU64,
+    rejected: AtomicU64,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+}
+impl ChannelCounters {
+    const ZERO: Self = Self {
+        messages: AtomicU64::new(0),
+        rejected: AtomicU64::new(0),
+        packets: AtomicU64::new(0),
+        bytes: AtomicU64::new(0),
+        packets_received: AtomicU64::new(0),
+        bytes_received: AtomicU64::new(0),
+    };
+}
+static TRAFFIC: [ChannelCounters; CHANNEL_NAMES.len()] =
+    [ChannelCounters::ZERO; CHANNEL_NAMES.len()];
+
+/// What this process has sent and received over one channel since it started.
+#[derive(Debug, Clone, Copy)]
+pub struct Traffic {
+    /// The name of the message type sent over the channel
+    pub name: &'static str,
+    /// Messages handed to `send` or `send_or_err`
+    pub messages: u64,
+    /// Messages that couldn't be sent, because the channel was full
+    pub rejected: u64,
+    /// Packets sent through a socket, which may hold many messages or parts of one
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets received from a socket, see `count_received`
+    pub packets_received: u64,
+    pub bytes_received: u64,
+}
+
+/// What this process has sent and received over each channel since it started.
+pub fn traffic() -> Vec<Traffic> {
+    CHANNEL_NAMES
+        .iter()
+        .zip(TRAFFIC.iter())
+        .map(|(&name, c)| Traffic {
+            name,
+            messages: c.messages.load(Ordering::Relaxed),
+            rejected: c.rejected.load(Ordering::Relaxed),
+            packets: c.packets.load(Ordering::Relaxed),
+            bytes: c.bytes.load(Ordering::Relaxed),
+            packets_received: c.packets_received.load(Ordering::Relaxed),
+            bytes_received: c.bytes_received.load(Ordering::Relaxed),
+        })
+        .collect()
+}
+
+/// The counters for the channel a packet is on.
+/// The multiplexer starts each packet with its channel.
+fn counters_for(packet: &[u8]) -> Option<&'static ChannelCounters> {
+    packet.first().and_then(|&channel| TRAFFIC.get(channel as usize))
+}
+
+/// Counts a packet headed for a socket.
+fn count_sent(packet: &[u8]) {
+    if let Some(c) = counters_for(packet) {
+        c.packets.fetch_add(1, Ordering::Relaxed);
+        c.bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counts a packet that has just come in from a socket, before it goes to its MessageChannels.
+pub fn count_received(packet: &[u8]) {
+    if let Some(c) = counters_for(packet) {
+        c.packets_received.fetch_add(1, Ordering::Relaxed);
+        c.bytes_received.fetch_add(packet.len() as u64, Ordering::Relaxed);
+    }
+}
+
 macro_rules! messages {
     ( use { $($use:tt)* }; $( (
         MessageChannelSettings {
@@ -44,6 +152,20 @@ macro_rules! messages {
             $( $name, )*
         }
 
+        /// The name of each message type, in the order of the channels they're sent on
+        pub const CHANNEL_NAMES: &[&str] = &[ $( stringify!($name), )* ];
+
+        /// The channel a message type is sent on, if it's one of ours
+        fn channel_of<M: 'static>() -> Option<usize> {
+            use std::any::TypeId;
+            $(
+            if TypeId::of::<M>() == TypeId::of::<$name>() {
+                return Some(Channel::$name as usize);
+            }
+            )*
+            None
+        }
+
         /// Creates a MessageChannels configured with our message types, and a multiplexer
         /// for sending messages into the channels
         pub fn channel_with_multiplexer(
@@ -82,11 +204,62 @@ const SENSIBLE_RELIABLE: MessageChannelMode = MessageChannelMode::Reliable {
     max_message_len: 1024,
 };
 
+/// Something that happened to an entity, which clients hear about in a WorldEvent
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum EntEvent {
+    /// The entity's id, and the tick it was at this position on
+    Spawn(u64, u32, glam::Vec2, Vec<crate::Comp>),
+    /// Components that have been added or changed
+    Update(u64, Vec<crate::Comp>),
+    /// Components that have been removed
+    Remove(u64, Vec<crate::CompKind>),
+    Despawn(u64),
+}
+
 messages! {
     use {
         serde::{Serialize, Deserialize},
         glam::Vec2,
     };
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct Handshake {
+            pub protocol_version: u32,
+            pub build_hash: String,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub enum HandshakeReply {
+            Accepted,
+            Rejected(String),
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        // Sent right after the Handshake, the server only replies once it has both
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct Introduction {
+            /// What the client would like to be known as, see `Name::problem`
+            pub name: String,
+            /// A secret which lets the client use commands like /kick, see the server's `op_tokens`
+            pub op_token: Option<String>,
+        }
+    ),
     (
         MessageChannelSettings {
             channel_mode: MessageChannelMode::Unreliable,
@@ -96,14 +269,31 @@ messages! {
         #[derive(Serialize, Deserialize, Debug)]
         pub struct Heartbeat;
     ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 32,
+            packet_buffer_size: 32,
+        }
+        #[derive(Serialize, Deserialize, Clone, Debug)]
+        pub struct Chat {
+            pub scope: crate::ChatScope,
+            /// The name of whoever sent this, filled in by the server
+            pub from: String,
+            pub text: String,
+        }
+    ),
     (
         MessageChannelSettings {
             channel_mode: SENSIBLE_RELIABLE,
             message_buffer_size: 8,
             packet_buffer_size: 8,
         }
-        #[derive(Serialize, Deserialize, Clone, Debug)]
-        pub struct Chat(pub String);
+        // Sent by whichever side is ending the connection, so the other knows why
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct Disconnect {
+            pub reason: String,
+        }
     ),
     (
         MessageChannelSettings {
@@ -111,24 +301,86 @@ messages! {
             message_buffer_size: 8,
             packet_buffer_size: 8,
         }
+        // Like InputAck and SnapshotAck, these are unreliable, so old ones may turn up after new
+        // ones, and any that aren't newer than the last one received are ignored
         #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-        pub struct Move {
-            pub id: u64,
+        pub struct Input {
+            /// Goes up by one with each Input a client sends
+            pub seq: u32,
+            /// The direction the client would like their island to move in
+            pub dir: Vec2,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+        pub struct InputAck {
+            /// The newest Input the server has applied to the client's island
+            pub seq: u32,
+            /// Where the client's island ended up after that Input was applied
+            pub pos: Vec2,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 32,
+            packet_buffer_size: 32,
+        }
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct Snapshot {
+            /// Which of the client's Worlds these positions are from, see `WorldJoin::epoch`
+            pub epoch: u32,
+            /// The tick on which these positions were recorded
             pub tick: u32,
-            pub pos: Vec2
+            /// Snapshots are split into parts so that each fits in a single packet,
+            /// the client may only acknowledge a tick once it has every part.
+            pub part: u16,
+            pub parts: u16,
+            /// Everything that has moved since the newest Snapshot the client acknowledged
+            pub moves: Vec<(u64, Vec2)>,
         }
     ),
     (
         MessageChannelSettings {
-            channel_mode: SENSIBLE_RELIABLE,
+            channel_mode: MessageChannelMode::Unreliable,
             message_buffer_size: 8,
             packet_buffer_size: 8,
         }
+        // Sent once the client has every part of the Snapshot for this tick
         #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
-        pub enum EntEvent {
-            Spawn(u64, Vec2, crate::Art),
-            Despawn(u64),
+        pub struct SnapshotAck {
+            pub epoch: u32,
+            pub tick: u32,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 64,
+            packet_buffer_size: 64,
+        }
+        // Something that happened to an entity in the client's World
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct WorldEvent {
+            /// Which of the client's Worlds it happened in, see `WorldJoin::epoch`
+            pub epoch: u32,
+            pub event: crate::EntEvent,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
         }
+        // Asks the server to move the client's island to the World with this name
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct TravelTo(pub String);
     ),
     (
         MessageChannelSettings {
@@ -138,27 +390,144 @@ messages! {
         }
         #[derive(Serialize, Deserialize, Debug)]
         pub struct WorldJoin {
-            pub islands: Vec<(u64, Vec2, crate::Art)>,
+            /// How many Worlds the client has left before this one. Entity ids are only unique
+            /// within a World, so anything about the entities of another is tagged with its own.
+            pub epoch: u32,
+            /// The client's own island, everything else arrives as EntEvent::Spawns
+            pub islands: Vec<(u64, Vec2, Vec<crate::Comp>)>,
             pub your_island: u64,
             pub world_name: String,
             pub tick: u32,
+            /// How long each of the server's ticks lasts
+            pub tick_ms: u32,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: SENSIBLE_RELIABLE,
+            message_buffer_size: MAX_PAST_CHATS,
+            packet_buffer_size: MAX_PAST_CHATS,
+        }
+        // A Chat sent in a World before the client joined it, these follow the WorldJoin
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct PastChat {
+            /// How many seconds ago it was sent
+            pub age_secs: u32,
+            pub chat: Chat,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        // Sent by the server every so often, the client replies with a Pong
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+        pub struct Ping {
+            pub id: u32,
+            /// How long the last round trip took, so the client knows too
+            pub last_rtt_ms: Option<u32>,
+        }
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        // The id of the Ping being replied to
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+        pub struct Pong(pub u32);
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        // Sent by the client every so often to ask what tick it is, so its Clock keeps in step
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+        pub struct TimeRequest(pub u32);
+    ),
+    (
+        MessageChannelSettings {
+            channel_mode: MessageChannelMode::Unreliable,
+            message_buffer_size: 8,
+            packet_buffer_size: 8,
+        }
+        // Answers a TimeRequest with the tick the client's World was on when it was answered
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+        pub struct TimeReply {
+            /// The id of the TimeRequest being replied to
+            pub id: u32,
+            pub tick: u32,
         }
     ),
 }
 
-/// Spawns a new task which sends all packages from an Outgoing channel into a UDP socket.
+impl Handshake {
+    /// The Handshake this build should introduce itself with.
+    pub fn ours() -> Self {
+        Self { protocol_version: PROTOCOL_VERSION, build_hash: BUILD_HASH.to_string() }
+    }
+
+    /// Returns the reason a remote introducing itself with this Handshake can't talk to us,
+    /// or None if it can.
+    pub fn mismatch(&self) -> Option<String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            Some(format!(
+                "protocol mismatch: server speaks v{}, client speaks v{}",
+                PROTOCOL_VERSION, self.protocol_version
+            ))
+        } else if self.build_hash != BUILD_HASH {
+            Some(format!("build mismatch: server is {}, client is {}", BUILD_HASH, self.build_hash))
+        } else {
+            None
+        }
+    }
+}
+
+impl Chat {
+    /// A Chat for a client to send, the server fills in who it's from.
+    pub fn new(scope: ChatScope, text: impl ToString) -> Self {
+        Self { scope, from: String::new(), text: text.to_string() }
+    }
+
+    /// A Chat from the server itself.
+    pub fn system(text: impl ToString) -> Self {
+        Self { scope: ChatScope::System, from: String::new(), text: text.to_string() }
+    }
+}
+
+/// Spawns a new task which sends all packages from an Outgoing channel into a UDP socket,
+/// by way of a simulated link unless the conditions are perfect.
 pub fn send_outgoing_to_socket(
-    mut outgoing: turbulence::OutgoingMultiplexedPackets<BufferPacket<Box<[u8]>>>,
+    outgoing: turbulence::OutgoingMultiplexedPackets<BufferPacket<Box<[u8]>>>,
     socket: smol::net::UdpSocket,
     to: std::net::SocketAddr,
+    conditions: crate::link::LinkConditions,
 ) {
-    GlobalSmolRuntime.spawn(async move {
-        while let Some(p) = outgoing.next().await {
+    async fn send_all<P: std::ops::Deref<Target = [u8]>>(
+        mut packets: impl smol::stream::Stream<Item = P> + Unpin,
+        socket: smol::net::UdpSocket,
+        to: std::net::SocketAddr,
+    ) {
+        while let Some(p) = packets.next().await {
+            count_sent(&p);
             if let Err(e) = socket.send_to(&p, to).await {
                 println!("couldn't send: {}", e);
             }
         }
-    });
+    }
+
+    if conditions.is_perfect() {
+        GlobalSmolRuntime.spawn(send_all(outgoing, socket, to));
+    } else {
+        let packets = outgoing.map(|p| p.to_vec());
+        let arrived = crate::link::simulate(packets, conditions, crate::link::random_seed());
+        GlobalSmolRuntime.spawn(send_all(arrived, socket, to));
+    }
 }
 
 /// A smol::Timer wrapped to produce `()` instead of `Instant`,
@@ -222,3 +591,16 @@ pub fn acquire_max(
     packet.resize(1024, 0);
     packet
 }
+
+/// Returns a new Packet containing a copy of these bytes.
+pub fn packet_from(
+    pool: &turbulence::BufferPacketPool<SimpleBufferPool>,
+    bytes: &[u8],
+) -> turbulence::BufferPacket<Box<[u8]>> {
+    use turbulence::{Packet, PacketPool};
+
+    let mut packet = pool.acquire();
+    packet.resize(bytes.len(), 0);
+    packet.copy_from_slice(bytes);
+    packet
+}
@@ -0,0 +1,138 @@
+use crate::{Input, InputAck};
+use glam::Vec2;
+use std::collections::VecDeque;
+use turbulence::MessageChannels;
+
+/// About three seconds worth of ticks, any more and the server probably isn't listening.
+const MAX_UNACKED: usize = 64;
+
+/// Moves the local player's island as soon as they ask it to,
+/// instead of waiting for the server to tell us where it went.
+///
+/// Inputs the server hasn't acknowledged yet are kept so that they can be replayed
+/// on top of each authoritative position the server sends back.
+pub struct Predictor {
+    /// Where the island was a tick ago, kept for smoothing between ticks.
+    last_pos: Vec2,
+    pos: Vec2,
+    seq: u32,
+    /// The sequence number of the newest Input the server has acknowledged.
+    acked: u32,
+    last_tick: u32,
+    tick_ms: u32,
+    unacked: VecDeque<Input>,
+}
+impl Predictor {
+    /// `seq` should carry on from any previous Predictor, so that acks for
+    /// Inputs sent to a world we've since left can't be mistaken for new ones.
+    pub fn new(pos: Vec2, tick: u32, tick_ms: u32, seq: u32) -> Self {
+        Self {
+            last_pos: pos,
+            pos,
+            seq,
+            acked: seq,
+            last_tick: tick,
+            tick_ms,
+            unacked: VecDeque::with_capacity(MAX_UNACKED),
+        }
+    }
+
+    /// The sequence number of the last Input sent.
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Once per tick, sends the server the direction the player would like to move in,
+    /// and moves the island in that direction without waiting for a response.
+    pub fn steer(&mut self, channel: &mut MessageChannels, tick: u32, dir: Vec2) {
+        if let Some(input) = self.predict(tick, dir) {
+            crate::send_or_err(channel, input);
+        }
+    }
+
+    /// Moves the island a step in `dir`, returning the Input for the server to do the same,
+    /// unless a step has already been taken on this tick.
+    fn predict(&mut self, tick: u32, dir: Vec2) -> Option<Input> {
+        if tick == self.last_tick {
+            return None;
+        }
+        self.last_tick = tick;
+        self.seq += 1;
+
+        let input = Input { seq: self.seq, dir };
+        self.last_pos = self.pos;
+        self.pos = step(self.pos, dir, self.tick_ms);
+        if self.unacked.len() == MAX_UNACKED {
+            self.unacked.pop_front();
+        }
+        self.unacked.push_back(input);
+        Some(input)
+    }
+
+    /// Rewinds to the newest position the server has acknowledged,
+    /// then replays every Input it hasn't gotten to yet on top of that.
+    pub fn reconcile(&mut self, channel: &mut MessageChannels) {
+        let mut newest: Option<InputAck> = None;
+        while let Some(ack) = channel.recv::<InputAck>() {
+            if ack.seq > newest.map_or(self.acked, |n| n.seq) {
+                newest = Some(ack);
+            }
+        }
+
+        if let Some(ack) = newest {
+            self.correct(ack);
+        }
+    }
+
+    /// The server applies each Input as a step of its own, however many arrive in a tick,
+    /// so replaying the ones it hasn't gotten to yet lands right where they were predicted to.
+    fn correct(&mut self, InputAck { seq, pos: server_pos }: InputAck) {
+        let Self { pos, acked, tick_ms, unacked, .. } = self;
+        *acked = seq;
+        unacked.retain(|i| i.seq > seq);
+        *pos = unacked.iter().fold(server_pos, |p, i| step(p, i.dir, *tick_ms));
+    }
+
+    /// Where the island should be drawn, `fraction` of the way through the current tick.
+    pub fn pos_lerp(&self, fraction: f32) -> Vec2 {
+        self.last_pos.lerp(self.pos, fraction.min(1.0))
+    }
+}
+
+/// Mirrors the server's movement of an island over a single tick.
+fn step(pos: Vec2, dir: Vec2, tick_ms: u32) -> Vec2 {
+    pos + crate::island_velocity(dir) * (tick_ms as f32 / 1000.0)
+}
+
+#[test]
+fn predictions_hold_up() {
+    use glam::vec2;
+    let tick_ms = 50;
+    let mut you = Predictor::new(Vec2::zero(), 0, tick_ms, 0);
+    let (mut applied, mut server_pos) = (0, Vec2::zero());
+    let mut in_flight = VecDeque::new();
+
+    for tick in 1..=60 {
+        let dir = if tick < 30 { vec2(1.0, 0.0) } else { vec2(0.6, -0.8) };
+        in_flight.extend(you.predict(tick, dir));
+
+        // Inputs turn up unevenly, none on some ticks and two on others,
+        // so the server may be a few Inputs behind when it acknowledges one
+        for _ in 0..tick % 3 {
+            if let Some(Input { seq, dir }) = in_flight.pop_front() {
+                applied = seq;
+                server_pos = step(server_pos, dir, tick_ms);
+            }
+        }
+
+        let predicted = you.pos;
+        you.correct(InputAck { seq: applied, pos: server_pos });
+        assert_eq!(you.pos, predicted, "on tick {}", tick);
+    }
+
+    // the server has the final say, and everything not yet applied is kept on top of it
+    let unapplied = you.pos - server_pos;
+    let pushed = server_pos + vec2(0.0, 3.0);
+    you.correct(InputAck { seq: applied, pos: pushed });
+    assert!((you.pos - (pushed + unapplied)).length() < 1e-4);
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+/// Declares which components the server shares with clients.
+///
+/// Adding a line here is all it takes for a component on the server's entities to reach
+/// the client's Ents, it just needs to be Serialize, Deserialize, Clone, PartialEq and Debug.
+macro_rules! replicated {
+    ( $( $name:ident($ty:ty), )* ) => {
+        /// The value of a single replicated component.
+        #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+        pub enum Comp {
+            $( $name($ty), )*
+        }
+
+        /// Identifies a type of replicated component, without a value.
+        #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+        pub enum CompKind {
+            $( $name, )*
+        }
+
+        impl Comp {
+            pub fn kind(&self) -> CompKind {
+                match self {
+                    $( Comp::$name(_) => CompKind::$name, )*
+                }
+            }
+        }
+
+        $(
+            impl Replicated for $ty {
+                #[allow(unreachable_patterns)]
+                fn from_comp(comp: &Comp) -> Option<&Self> {
+                    match comp {
+                        Comp::$name(c) => Some(c),
+                        _ => None,
+                    }
+                }
+            }
+        )*
+
+        /// Collects every replicated component an entity has.
+        #[cfg(feature = "server")]
+        pub fn gather(ecs: &hecs::World, ent: hecs::Entity, out: &mut Vec<Comp>) {
+            $(
+                if let Ok(c) = ecs.get::<$ty>(ent) {
+                    out.push(Comp::$name((*c).clone()));
+                }
+            )*
+        }
+
+        /// Gives an entity the component inside of a Comp.
+        #[cfg(feature = "server")]
+        pub fn insert(
+            ecs: &mut hecs::World,
+            ent: hecs::Entity,
+            comp: Comp,
+        ) -> Result<(), hecs::NoSuchEntity> {
+            match comp {
+                $( Comp::$name(c) => ecs.insert_one(ent, c), )*
+            }
+        }
+    };
+}
+
+replicated! {
+    Art(crate::Art),
+    Name(crate::Name),
+    Interp(crate::Interp),
+    Velocity(crate::Velocity),
+}
+
+/// Implemented for every type listed in `replicated!`,
+/// so that they can be pulled back out of a list of Comps.
+pub trait Replicated: Sized {
+    fn from_comp(comp: &Comp) -> Option<&Self>;
+}
+
+/// Finds the component of type `T` in a list of Comps, if there is one.
+pub fn get<T: Replicated>(comps: &[Comp]) -> Option<&T> {
+    comps.iter().find_map(T::from_comp)
+}
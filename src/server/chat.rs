@@ -0,0 +1,139 @@
+use crate::{
+    commands::{Caller, Invocation},
+    config::Config,
+    moderation::Moderation,
+    net::Session,
+};
+use comn::{Chat, ChatScope};
+use fxhash::{FxHashMap, FxHashSet};
+
+/// Collects the chats clients send each tick, then routes each to the clients it's meant for.
+pub struct ChatDispatcher {
+    /// Chats for everyone on the server
+    global: Vec<Chat>,
+    /// Chats for the clients in a single World, by the name of that World
+    worlds: FxHashMap<String, Vec<Chat>>,
+    /// Chats for a single client, by their name
+    whispers: FxHashMap<String, Vec<Chat>>,
+    /// Whispers waiting for `route` to find out if whoever they're for is online
+    pending: Vec<Chat>,
+    /// The name of every client heard from this tick
+    online: FxHashSet<String>,
+    /// Chats that started with a `/`, which are run by Commands instead of being passed on
+    commands: Vec<Invocation>,
+    pub moderation: Moderation,
+}
+impl ChatDispatcher {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            global: Vec::with_capacity(10),
+            worlds: FxHashMap::default(),
+            whispers: FxHashMap::default(),
+            pending: Vec::with_capacity(10),
+            online: FxHashSet::default(),
+            commands: Vec::with_capacity(10),
+            moderation: Moderation::new(config),
+        }
+    }
+
+    /// Forgets the last tick's chats, call this before `fill`ing any worlds.
+    pub fn clear(&mut self) {
+        self.global.clear();
+        self.worlds.clear();
+        self.whispers.clear();
+        self.online.clear();
+        self.moderation.prune();
+    }
+
+    /// Collects the chats sent by the clients in a World.
+    pub fn fill<'a>(&mut self, world: &str, clients: impl Iterator<Item = &'a mut Session>) {
+        for Session { channel, name, op, addr, .. } in clients {
+            self.online.insert(name.clone());
+            // spammers are only told to slow down once a tick
+            let mut throttled = false;
+            while let Some(Chat { scope, text, .. }) = channel.recv() {
+                if let Err(reason) = self.moderation.throttle(name) {
+                    if !throttled {
+                        throttled = true;
+                        self.tell(name, Chat::system(reason));
+                    }
+                    continue;
+                }
+
+                log::info!("{} > {} said {} ({:?})", world, name, text, scope);
+                if scope == ChatScope::World && text.starts_with('/') {
+                    let caller =
+                        Caller::Client { name: name.clone(), world: world.to_string(), op: *op };
+                    self.commands.push(Invocation { caller, line: text });
+                    continue;
+                }
+
+                if let Err(reason) = self.moderation.check(addr.ip(), &text) {
+                    log::info!("{} > dropped chat from {}: {}", world, name, reason);
+                    self.tell(name, Chat::system(reason));
+                    continue;
+                }
+
+                // clients don't get to decide who they are
+                let chat = Chat { scope, from: name.clone(), text };
+                match &chat.scope {
+                    ChatScope::World => {
+                        self.worlds.entry(world.to_string()).or_default().push(chat)
+                    }
+                    ChatScope::Global => self.global.push(chat),
+                    ChatScope::Whisper(_) => self.pending.push(chat),
+                    ChatScope::System => {
+                        self.tell(name, Chat::system("Only the server can send system messages."))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes each whisper on to whoever it's for, and back to whoever sent it,
+    /// or tells the sender that nobody by that name is online.
+    /// Call this once every world has been `fill`ed.
+    pub fn route(&mut self) {
+        let Self { pending, whispers, online, .. } = self;
+        for chat in pending.drain(..) {
+            let to = match &chat.scope {
+                ChatScope::Whisper(to) => to.clone(),
+                _ => continue,
+            };
+
+            if !online.contains(&to) {
+                let refusal = Chat::system(format!("Nobody named {:?} is online.", to));
+                whispers.entry(chat.from).or_default().push(refusal);
+                continue;
+            }
+            if to != chat.from {
+                whispers.entry(chat.from.clone()).or_default().push(chat.clone());
+            }
+            whispers.entry(to).or_default().push(chat);
+        }
+    }
+
+    /// Queues a Chat for a single client, which is sent along with the rest in `sync`.
+    pub fn tell(&mut self, name: &str, chat: Chat) {
+        self.whispers.entry(name.to_string()).or_default().push(chat);
+    }
+
+    /// The slash commands clients sent this tick, see `fill`.
+    pub fn take_commands(&mut self) -> Vec<Invocation> {
+        std::mem::take(&mut self.commands)
+    }
+
+    /// The chats sent to everyone in the named World this tick.
+    pub fn world_chats(&self, world: &str) -> &[Chat] {
+        self.worlds.get(world).map_or(&[], |chats| chats.as_slice())
+    }
+
+    /// Sends a client in the named World every chat meant for them.
+    pub fn sync(&self, world: &str, Session { channel, name, .. }: &mut Session) {
+        let local = self.worlds.get(world).into_iter().flatten();
+        let whispers = self.whispers.get(name.as_str()).into_iter().flatten();
+        for chat in self.global.iter().chain(local).chain(whispers) {
+            comn::send_or_err(channel, chat.clone());
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{
+    net::{Farewells, Session},
+    ChatDispatcher, WorldRegistry,
+};
+use comn::Chat;
+use std::net::IpAddr;
+
+/// Whoever is running a command.
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// Whoever is typing into the server's terminal, who may run anything
+    Console,
+    Client {
+        name: String,
+        world: String,
+        /// Whether they introduced themselves with an op token, see `Config::op_tokens`
+        op: bool,
+    },
+}
+
+/// A line of text to be run as a command, the leading `/` is optional.
+#[derive(Debug)]
+pub struct Invocation {
+    pub caller: Caller,
+    pub line: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Permission {
+    Anyone,
+    /// Only the console and the clients who introduced themselves with an op token
+    Op,
+}
+
+/// Everything a command needs to do its job.
+struct Context<'a> {
+    caller: &'a Caller,
+    commands: &'a Commands,
+    worlds: &'a mut WorldRegistry,
+    chat: &'a mut ChatDispatcher,
+    farewells: &'a mut Farewells,
+}
+
+/// Returns the text to reply to the caller with, or why the command couldn't be run.
+type Handler = fn(&mut Context, &[&str]) -> Result<String, String>;
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    /// How many arguments must be given, any more are left to the handler
+    required: usize,
+    permission: Permission,
+    handler: Handler,
+}
+
+/// The commands clients can run by chatting a line starting with `/`,
+/// and which the console can run by typing the same line.
+pub struct Commands {
+    list: Vec<Command>,
+}
+impl Commands {
+    pub fn new() -> Self {
+        let mut commands = Self { list: Vec::with_capacity(10) };
+
+        use Permission::*;
+        commands.register("help", "", "lists the commands you can use", 0, Anyone, help);
+        commands.register("who", "", "lists who is in each world", 0, Anyone, who);
+        commands.register(
+            "world",
+            "",
+            "tells you where you are, and where you can go",
+            0,
+            Anyone,
+            world,
+        );
+        commands.register("tp", "<name> <world>", "moves a client to another world", 2, Op, tp);
+        commands.register("kick", "<name> [reason]", "boots a client off the server", 1, Op, kick);
+        commands.register("mute", "<name>", "stops a client's address from chatting", 1, Op, mute);
+        commands.register(
+            "unmute",
+            "<name or address>",
+            "lets a muted address chat again",
+            1,
+            Op,
+            unmute,
+        );
+        commands
+    }
+
+    fn register(
+        &mut self,
+        name: &'static str,
+        usage: &'static str,
+        description: &'static str,
+        required: usize,
+        permission: Permission,
+        handler: Handler,
+    ) {
+        self.list.push(Command { name, usage, description, required, permission, handler });
+    }
+
+    fn permits(&self, caller: &Caller, command: &Command) -> bool {
+        match (command.permission, caller) {
+            (Permission::Anyone, _) | (Permission::Op, Caller::Console) => true,
+            (Permission::Op, Caller::Client { op, .. }) => *op,
+        }
+    }
+
+    /// Runs a command, then replies to whoever ran it and only them.
+    pub fn run(
+        &self,
+        Invocation { caller, line }: Invocation,
+        worlds: &mut WorldRegistry,
+        chat: &mut ChatDispatcher,
+        farewells: &mut Farewells,
+    ) {
+        log::info!("{:?} ran {}", caller, line);
+        let mut context = Context { caller: &caller, commands: self, worlds, chat, farewells };
+        let reply = match self.execute(&mut context, &line) {
+            Ok(reply) | Err(reply) => reply,
+        };
+
+        for line in reply.lines() {
+            match &caller {
+                Caller::Console => log::info!("{}", line),
+                Caller::Client { name, .. } => chat.tell(name, Chat::system(line)),
+            }
+        }
+    }
+
+    fn execute(&self, context: &mut Context, line: &str) -> Result<String, String> {
+        let mut words = line.trim().trim_start_matches('/').split_whitespace();
+        let name = words.next().ok_or_else(|| "Try /help.".to_string())?;
+        let command = self
+            .list
+            .iter()
+            .find(|command| command.name == name)
+            .ok_or_else(|| format!("There's no command named {:?}, try /help.", name))?;
+
+        if !self.permits(context.caller, command) {
+            return Err(format!("You aren't allowed to use /{}.", name));
+        }
+        let args: Vec<&str> = words.collect();
+        if args.len() < command.required {
+            return Err(format!("usage: /{} {}", command.name, command.usage));
+        }
+
+        (command.handler)(context, &args)
+    }
+}
+
+fn help(Context { caller, commands, .. }: &mut Context, _: &[&str]) -> Result<String, String> {
+    Ok(commands
+        .list
+        .iter()
+        .filter(|command| commands.permits(caller, command))
+        .map(|c| format!("/{} {} - {}", c.name, c.usage, c.description))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+fn who(Context { worlds, .. }: &mut Context, _: &[&str]) -> Result<String, String> {
+    let lines: Vec<String> = worlds
+        .worlds()
+        .filter(|world| world.is_occupied())
+        .map(|world| {
+            let names: Vec<String> =
+                world.ecs.clients().iter().map(|(_, s)| s.name.clone()).collect();
+            format!("{}: {}", world.name, names.join(", "))
+        })
+        .collect();
+
+    if lines.is_empty() {
+        Ok("Nobody is online.".to_string())
+    } else {
+        Ok(lines.join("\n"))
+    }
+}
+
+fn world(Context { caller, worlds, .. }: &mut Context, _: &[&str]) -> Result<String, String> {
+    let max = worlds.max_clients;
+    let named: Vec<String> = worlds
+        .named
+        .iter()
+        .map(|world| format!("{} ({}/{})", world.name, world.client_count(), max))
+        .collect();
+    let destinations = format!("Worlds: {}", named.join(", "));
+
+    match caller {
+        Caller::Client { world, .. } => Ok(format!("You're in {}.\n{}", world, destinations)),
+        Caller::Console => Ok(destinations),
+    }
+}
+
+fn tp(Context { worlds, .. }: &mut Context, args: &[&str]) -> Result<String, String> {
+    let (name, to) = (args[0], args[1..].join(" "));
+    let (from, ent) = worlds
+        .find_client(name)
+        .map(|(world, ent)| (world.name.clone(), ent))
+        .ok_or_else(|| format!("Nobody named {:?} is online.", name))?;
+
+    worlds.transfer(&from, ent, &to)?;
+    Ok(format!("Moved {} from {} to {}.", name, from, to))
+}
+
+fn kick(Context { worlds, farewells, .. }: &mut Context, args: &[&str]) -> Result<String, String> {
+    let name = args[0];
+    let reason = if args.len() > 1 { args[1..].join(" ") } else { "kicked by an op".to_string() };
+
+    let (world, ent) =
+        worlds.find_client(name).ok_or_else(|| format!("Nobody named {:?} is online.", name))?;
+    world.kick(ent, &reason, farewells);
+    Ok(format!("Kicked {} ({}).", name, reason))
+}
+
+/// The address of whoever is online by this name, since that's what mutes go by.
+fn address_of(worlds: &mut WorldRegistry, name: &str) -> Option<IpAddr> {
+    let (world, ent) = worlds.find_client(name)?;
+    let session = world.ecs.get::<Session>(ent).ok()?;
+    Some(session.addr.ip())
+}
+
+fn mute(Context { worlds, chat, .. }: &mut Context, args: &[&str]) -> Result<String, String> {
+    let name = args[0];
+    let addr =
+        address_of(worlds, name).ok_or_else(|| format!("Nobody named {:?} is online.", name))?;
+    if chat.moderation.mute(addr) {
+        Ok(format!("Muted {} ({}).", name, addr))
+    } else {
+        Err(format!("{} ({}) is already muted.", name, addr))
+    }
+}
+
+fn unmute(Context { worlds, chat, .. }: &mut Context, args: &[&str]) -> Result<String, String> {
+    let who = args[0];
+    let addr = address_of(worlds, who)
+        .or_else(|| who.parse().ok())
+        .ok_or_else(|| format!("Nobody named {:?} is online, and it isn't an address.", who))?;
+    if chat.moderation.unmute(addr) {
+        Ok(format!("Unmuted {}.", addr))
+    } else {
+        Err(format!("{} isn't muted.", addr))
+    }
+}
@@ -0,0 +1,232 @@
+use crate::schedule::Overrun;
+use comn::link::LinkConditions;
+use serde::Deserialize;
+use std::{fmt::Display, net::IpAddr, path::Path, str::FromStr, time::Duration};
+
+/// Read if it exists and no other config file is specified with `--config`.
+const DEFAULT_PATH: &str = "server.ron";
+
+const USAGE: &str = "\
+usage: server [options]
+
+options:
+    --config <path>              RON file to read settings from, defaults to server.ron
+    --bind <addr>                address to listen for clients on
+    --tick-ms <ms>               how long each server tick lasts
+    --pool-size <bytes>          size of each buffer in the packet pool
+    --max-clients <n>            how many new clients are put in the same starter world
+    --world-capacity <n>         how many clients may be in each persistent world
+    --heartbeat-timeout <secs>   how long a silent client is kept before being booted
+    --view-radius <units>        how far away from their island clients can see things
+    --save-dir <path>            directory the persistent worlds are saved in
+    --autosave <secs>            how often the persistent worlds are saved
+    --metrics <addr>             address to serve Prometheus metrics on, off if left out
+    --overrun <policy>           what to do about slow ticks: catch-up, skip or slow-down
+    --chat-rate <per sec>        how many chats each client may send per second
+    --chat-burst <n>             how many chats each client may send all at once
+    --chat-max-len <chars>       how long each chat may be
+    --chat-backlog <n>           how many old chats clients are sent when joining a world
+
+simulating a bad connection to every client, for testing:
+    --latency <ms>               how long each packet takes to arrive
+    --jitter <ms>                how much longer than that it might take
+    --loss <chance>              how likely each packet is to be lost, from 0 to 1
+    --duplicate <chance>         how likely each packet is to arrive twice
+    --reorder <chance>           how likely each packet is to arrive after those sent after it
+    --bandwidth <bytes per sec>  how much can be sent to or from each client, 0 for no limit
+
+every option except --config can also be set in the config file, i.e.
+    (bind: \"0.0.0.0:1337\", tick_ms: 50, max_clients_per_world: 8)
+where each is named like the option with underscores for dashes, apart from
+    --max-clients                max_clients_per_world
+    --heartbeat-timeout          heartbeat_timeout_secs
+    --autosave                   autosave_secs
+    --metrics                    metrics_bind
+    --overrun                    overrun, which is one of CatchUp, Skip or SlowDown
+    --latency, --jitter          latency_ms and jitter_ms, inside link (see below)
+    --loss, --duplicate, ...     loss, duplicate, reorder and bandwidth, inside link
+
+the config file can also list the persistent worlds clients may travel to,
+the secret tokens which let clients use commands like /kick and /tp (see the client's --op-token),
+the words chats may not contain and the addresses of clients who start out muted, i.e.
+    (worlds: [\"Hub\", \"Arena\"], op_tokens: [\"hunter2\"],
+     chat_blocklist: [\"heck\"], muted: [\"10.0.0.7\"])
+and the simulated connection, i.e.
+    (link: (latency_ms: 100, loss: 0.05))";
+
+/// Everything about the server that can be tweaked without recompiling.
+/// Unknown keys are refused, so that settings which have been renamed don't go quietly ignored.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the server's UDP socket listens on.
+    pub bind: String,
+    /// How long a single server tick lasts.
+    pub tick_ms: u32,
+    /// What to do once ticks have been taking so long that the server has fallen behind.
+    pub overrun: Overrun,
+    /// The size of each buffer in the packet pool.
+    pub pool_size: usize,
+    /// How many newly connected clients are put in the same Starter World.
+    pub max_clients_per_world: usize,
+    /// How many clients may be in each of the persistent worlds at once.
+    pub world_capacity: usize,
+    /// How long a client can go without sending a Heartbeat before they're booted.
+    pub heartbeat_timeout_secs: f32,
+    /// How far away from their island clients are told about things.
+    pub view_radius: f32,
+    /// The names of the persistent worlds, which stick around even when nobody is in them.
+    pub worlds: Vec<String>,
+    /// Secrets which let the clients who introduce themselves with one use commands like /kick
+    /// and /tp. Names can't be used for this, since clients are free to ask for any name.
+    pub op_tokens: Vec<String>,
+    /// How many chats each client may send per second, once they've used up their burst.
+    pub chat_rate: f32,
+    /// How many chats each client may send all at once.
+    pub chat_burst: f32,
+    /// The most characters a single chat may contain.
+    pub chat_max_len: usize,
+    /// How many of the newest chats in a World are sent to clients joining it,
+    /// at most `comn::MAX_PAST_CHATS`.
+    pub chat_backlog: usize,
+    /// Chats containing any of these words are dropped, regardless of case.
+    pub chat_blocklist: Vec<String>,
+    /// The addresses of the clients who may not chat until they're unmuted with /unmute.
+    pub muted: Vec<IpAddr>,
+    /// The directory the persistent worlds are saved in and loaded from.
+    pub save_dir: String,
+    /// How often the persistent worlds are saved while the server is running.
+    pub autosave_secs: f32,
+    /// The address metrics are served on over HTTP, in the Prometheus text format.
+    /// Empty if they aren't served at all.
+    pub metrics_bind: String,
+    /// How badly packets to and from every client are mistreated, to see how things hold up
+    /// over a bad connection. These stack with any conditions a client simulates itself.
+    pub link: LinkConditions,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: comn::SERVER.to_string(),
+            tick_ms: comn::SERVER_TICK_MS,
+            overrun: Overrun::CatchUp,
+            pool_size: 2500,
+            max_clients_per_world: 1,
+            world_capacity: 16,
+            heartbeat_timeout_secs: 3.0,
+            view_radius: 10.0,
+            worlds: vec!["Hub".to_string()],
+            op_tokens: vec![],
+            chat_rate: 1.0,
+            chat_burst: 5.0,
+            chat_max_len: 200,
+            chat_backlog: 20,
+            chat_blocklist: vec![],
+            muted: vec![],
+            save_dir: "saves".to_string(),
+            autosave_secs: 60.0,
+            metrics_bind: String::new(),
+            link: LinkConditions::default(),
+        }
+    }
+}
+impl Config {
+    /// Reads the config file, then applies any overrides passed on the command line.
+    /// An Err contains a message suitable for showing to whoever ran the server.
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let args: Vec<String> = args.collect();
+
+        let path = args.iter().position(|a| a == "--config").and_then(|i| args.get(i + 1));
+        let mut config = match path {
+            Some(path) => Self::load(path)?,
+            None if Path::new(DEFAULT_PATH).exists() => Self::load(DEFAULT_PATH)?,
+            None => Self::default(),
+        };
+
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            if flag == "--help" || flag == "-h" {
+                return Err(USAGE.to_string());
+            }
+
+            let value =
+                args.next().ok_or_else(|| format!("{} needs a value\n\n{}", flag, USAGE))?;
+            match flag.as_str() {
+                "--config" => {}
+                "--bind" => config.bind = value.clone(),
+                "--tick-ms" => config.tick_ms = parse(flag, value)?,
+                "--overrun" => config.overrun = parse(flag, value)?,
+                "--pool-size" => config.pool_size = parse(flag, value)?,
+                "--max-clients" => config.max_clients_per_world = parse(flag, value)?,
+                "--world-capacity" => config.world_capacity = parse(flag, value)?,
+                "--heartbeat-timeout" => config.heartbeat_timeout_secs = parse(flag, value)?,
+                "--view-radius" => config.view_radius = parse(flag, value)?,
+                "--save-dir" => config.save_dir = value.clone(),
+                "--autosave" => config.autosave_secs = parse(flag, value)?,
+                "--metrics" => config.metrics_bind = value.clone(),
+                "--chat-rate" => config.chat_rate = parse(flag, value)?,
+                "--chat-burst" => config.chat_burst = parse(flag, value)?,
+                "--chat-max-len" => config.chat_max_len = parse(flag, value)?,
+                "--chat-backlog" => config.chat_backlog = parse(flag, value)?,
+                link if LinkConditions::FLAGS.contains(&link) => config.link.set(link, value)?,
+                _ => return Err(format!("unknown option {}\n\n{}", flag, USAGE)),
+            }
+        }
+
+        config.validate()
+    }
+
+    fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("couldn't read {}: {}", path.display(), e))?;
+        ron::de::from_str(&text).map_err(|e| format!("couldn't parse {}: {}", path.display(), e))
+    }
+
+    fn validate(self) -> Result<Self, String> {
+        if self.tick_ms == 0 {
+            Err("tick_ms must be at least 1".to_string())
+        } else if self.max_clients_per_world == 0 {
+            Err("max_clients_per_world must be at least 1".to_string())
+        } else if self.world_capacity == 0 {
+            Err("world_capacity must be at least 1".to_string())
+        } else if !(self.heartbeat_timeout_secs > 0.0) {
+            Err("heartbeat_timeout_secs must be positive".to_string())
+        } else if !(self.view_radius > 0.0) {
+            Err("view_radius must be positive".to_string())
+        } else if !(self.autosave_secs > 0.0) {
+            Err("autosave_secs must be positive".to_string())
+        } else if !(self.chat_rate > 0.0) {
+            Err("chat_rate must be positive".to_string())
+        } else if !(self.chat_burst >= 1.0) {
+            Err("chat_burst must be at least 1".to_string())
+        } else if self.chat_max_len == 0 {
+            Err("chat_max_len must be at least 1".to_string())
+        } else if self.chat_backlog > comn::MAX_PAST_CHATS {
+            Err(format!("chat_backlog can be at most {}", comn::MAX_PAST_CHATS))
+        } else if let Some(problem) = self.link.problem() {
+            Err(format!("link is wrong: {}", problem))
+        } else {
+            Ok(self)
+        }
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis(self.tick_ms as _)
+    }
+
+    pub fn heartbeat_timeout(&self) -> Duration {
+        Duration::from_secs_f32(self.heartbeat_timeout_secs)
+    }
+
+    pub fn autosave(&self) -> Duration {
+        Duration::from_secs_f32(self.autosave_secs)
+    }
+}
+
+fn parse<T: FromStr>(flag: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value.parse().map_err(|e| format!("invalid value {:?} for {}: {}", value, flag, e))
+}
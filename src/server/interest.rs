@@ -0,0 +1,92 @@
+use crate::net::Session;
+use comn::{EntEvent, WorldEvent};
+use fxhash::FxHashMap;
+use glam::Vec2;
+
+/// Things stay visible until they're this much further away than the view radius,
+/// so that anything on the edge of a client's view doesn't flicker in and out of existence.
+const LEAVE_FACTOR: f32 = 1.25;
+
+/// Decides which entities each client should hear about,
+/// based on how close they are to that client's island.
+pub struct Interest {
+    radius: f32,
+    /// Positions bucketed into square cells as wide as the view radius, so that finding
+    /// everything in view of a point only means searching the nine cells around it.
+    cells: FxHashMap<(i32, i32), Vec<(hecs::Entity, Vec2)>>,
+    /// Temporary buffer for the entities a client should stop hearing about.
+    leaving: Vec<u64>,
+}
+impl Interest {
+    pub fn new(radius: f32) -> Self {
+        Self { radius, cells: FxHashMap::default(), leaving: Vec::with_capacity(100) }
+    }
+
+    /// Whether something at `other` can be seen from `pos`.
+    pub fn in_view(&self, pos: Vec2, other: Vec2) -> bool {
+        (other - pos).length() <= self.radius
+    }
+
+    fn cell(&self, pos: Vec2) -> (i32, i32) {
+        ((pos.x() / self.radius).floor() as i32, (pos.y() / self.radius).floor() as i32)
+    }
+
+    /// Buckets everything in the world by where it is on this tick.
+    pub fn index(&mut self, ecs: &hecs::World) {
+        self.cells.retain(|_, cell| !cell.is_empty());
+        for cell in self.cells.values_mut() {
+            cell.clear();
+        }
+
+        for (e, &pos) in ecs.query::<&Vec2>().iter() {
+            let cell = self.cell(pos);
+            self.cells.entry(cell).or_default().push((e, pos));
+        }
+    }
+
+    /// Everything in view of `pos`, as of the last call to `index`.
+    pub fn near(&self, pos: Vec2) -> impl Iterator<Item = (hecs::Entity, Vec2)> + '_ {
+        let (cx, cy) = self.cell(pos);
+        (cx - 1..=cx + 1)
+            .flat_map(move |x| (cy - 1..=cy + 1).map(move |y| (x, y)))
+            .filter_map(move |cell| self.cells.get(&cell))
+            .flatten()
+            .copied()
+            .filter(move |&(_, p)| self.in_view(pos, p))
+    }
+
+    /// Spawns whatever has come into view of a client whose island is at `pos` on this `tick`,
+    /// and despawns whatever has gone out of it.
+    pub fn update(&mut self, ecs: &hecs::World, tick: u32, session: &mut Session, pos: Vec2) {
+        let Session { channel, epoch, visible, .. } = session;
+
+        for (e, p) in self.near(pos) {
+            let id = e.to_bits();
+            if !visible.contains(&id) {
+                let mut comps = Vec::with_capacity(4);
+                comn::gather(ecs, e, &mut comps);
+
+                // if the channel is full, this'll be tried again next tick
+                let event = EntEvent::Spawn(id, tick, p, comps);
+                if comn::send(channel, WorldEvent { epoch: *epoch, event }).is_none() {
+                    visible.insert(id);
+                }
+            }
+        }
+
+        let leave_radius = self.radius * LEAVE_FACTOR;
+        self.leaving.extend(visible.iter().copied().filter(|&id| {
+            match ecs.get::<Vec2>(hecs::Entity::from_bits(id)) {
+                Ok(p) => (*p - pos).length() > leave_radius,
+                Err(_) => true,
+            }
+        }));
+        for id in self.leaving.drain(..) {
+            // if the channel is full, it's still visible, so this'll be tried again next tick
+            let event = EntEvent::Despawn(id);
+            if comn::send(channel, WorldEvent { epoch: *epoch, event }).is_none() {
+                visible.remove(&id);
+            }
+        }
+    }
+}
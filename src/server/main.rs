@@ -1,39 +1,39 @@
 #![feature(drain_filter)]
 use comn::Chat;
-use std::time::Duration;
+use std::{collections::VecDeque, time::Duration};
 
+mod chat;
+use chat::ChatDispatcher;
+mod commands;
+use commands::{Caller, Commands, Invocation};
+mod config;
+use config::Config;
+mod moderation;
 mod net;
-use net::{open_socket, Session};
+use net::{open_socket, Farewells, Handshakes, Session};
+mod interest;
+use interest::Interest;
+mod replicate;
+use replicate::Replicator;
+mod snapshot;
+use snapshot::Snapshots;
+mod save;
+use save::{EntSave, WorldSave};
+mod schedule;
+use schedule::Scheduler;
+mod metrics;
+use metrics::Metrics;
 
 fn main() {
     pretty_env_logger::init();
-    smol::block_on(start());
-}
-
-pub struct ChatDispatcher {
-    /// All the chats accumulated in a single frame of client handling
-    frame: Vec<Chat>,
-}
-impl ChatDispatcher {
-    fn new() -> Self {
-        Self { frame: Vec::with_capacity(10) }
-    }
-
-    fn fill<'a>(&mut self, clients: impl Iterator<Item = &'a mut Session>) {
-        self.frame.clear();
-        for Session { channel, addr, .. } in clients {
-            while let Some(Chat(chat)) = channel.recv() {
-                log::info!("{} said {}", addr, chat);
-                self.frame.push(Chat(chat));
-            }
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
-    }
-
-    fn sync(&self, Session { channel, .. }: &mut Session) {
-        for chat in &self.frame {
-            comn::send_or_err(channel, chat.clone());
-        }
-    }
+    };
+    smol::block_on(start(config));
 }
 
 use glam::Vec2;
@@ -42,44 +42,70 @@ use hecs::Bundle;
 struct PlayerIsland {
     pos: Vec2,
     art: comn::Art,
+    name: comn::Name,
+    interp: comn::Interp,
+    velocity: comn::Velocity,
+    last_input: LastInput,
     session: Session,
 }
 impl PlayerIsland {
     fn new(pos: Vec2, session: Session) -> Self {
-        Self { pos, session, art: comn::Art::Island }
+        Self {
+            pos,
+            name: comn::Name(session.name.clone()),
+            session,
+            art: comn::Art::Island,
+            interp: comn::Interp::Hermite,
+            velocity: comn::Velocity { per_tick: Vec2::zero(), since: 0 },
+            last_input: LastInput { seq: 0, banked: 0 },
+        }
     }
 }
 
-struct LastPos(Vec2);
-struct LastPosTracker {
-    need_last: Vec<(hecs::Entity, Vec2)>,
-    messages: Vec<comn::Move>,
-}
-impl LastPosTracker {
-    fn new() -> Self {
-        Self { need_last: Vec::with_capacity(1000), messages: Vec::with_capacity(1000) }
-    }
+/// How many ticks of movement a client can have saved up, so that Inputs which were held up
+/// and then arrive together can all be applied without letting anybody move faster than that.
+const MAX_BANKED_STEPS: u32 = 8;
 
-    fn track(&mut self, ecs: &mut Ecs, tick: u32) {
-        let Self { messages, need_last } = self;
-        need_last.extend(ecs.query::<&_>().without::<LastPos>().iter().map(|(e, p)| (e, *p)));
-        for (e, pos) in need_last.drain(..) {
-            comn::or_err!(ecs.insert_one(e, LastPos(pos)));
-        }
+/// The sequence number of the newest Input received from a client,
+/// and how many more steps their island can take before it's moving too quickly.
+#[derive(Debug)]
+struct LastInput {
+    seq: u32,
+    banked: u32,
+}
 
-        messages.clear();
-        for (e, (&pos, last_pos)) in &mut ecs.query::<(&Vec2, &mut LastPos)>() {
-            if pos != last_pos.0 {
-                last_pos.0 = pos;
-                messages.push(comn::Move { id: e.to_bits(), tick, pos });
+/// Moves each client's island a step for every one of their Inputs, just as they predicted it.
+/// Any Inputs which never arrived are assumed to have been like the next one that did.
+/// `tick` is the one the islands are moving into, see `comn::Velocity`.
+fn steer(ecs: &mut hecs::World, tick: u32, tick_ms: u32) {
+    let dt = tick_ms as f32 / 1000.0;
+    for (_, (session, pos, vel, last)) in
+        &mut ecs.query::<(&mut Session, &mut Vec2, &mut comn::Velocity, &mut LastInput)>()
+    {
+        last.banked = (last.banked + 1).min(MAX_BANKED_STEPS);
+        while let Some(comn::Input { seq, dir }) = session.channel.recv() {
+            if seq <= last.seq {
+                continue;
+            }
+            let steps = (seq - last.seq).min(last.banked);
+            last.seq = seq;
+            last.banked -= steps;
+            let per_tick = comn::island_velocity(dir) * dt;
+            if per_tick != vel.per_tick {
+                *vel = comn::Velocity { per_tick, since: tick };
+            }
+            for _ in 0..steps {
+                *pos += per_tick;
             }
         }
     }
+}
 
-    fn sync(&self, Session { channel, .. }: &mut Session) {
-        for &message in &self.messages {
-            comn::send_or_err(channel, message);
-        }
+/// Tells each client where their island is after the newest Input we've applied,
+/// so that they can correct their predictions.
+fn acknowledge(ecs: &mut hecs::World) {
+    for (_, (session, &pos, last)) in &mut ecs.query::<(&mut Session, &Vec2, &LastInput)>() {
+        comn::send_or_err(&mut session.channel, comn::InputAck { seq: last.seq, pos });
     }
 }
 
@@ -102,26 +128,25 @@ impl Ecs {
     }
 
     /// Inserts the given island, returning its Entity.
-    /// Sends a message to all clients letting them know as much.
+    /// Clients close enough to see it will hear about it through `Interest`.
     fn add_island(&mut self, island: PlayerIsland) -> hecs::Entity {
-        use comn::{send_or_err, EntEvent};
-        let ent = self.0.reserve_entity();
-        let spawn_msg = EntEvent::Spawn(ent.to_bits(), island.pos, island.art);
-
-        comn::or_err!(self.0.insert(ent, island));
-        for (_, Session { channel, .. }) in self.clients_mut().iter().filter(|(e, _)| *e != ent) {
-            send_or_err(channel, spawn_msg)
-        }
-        ent
+        self.0.spawn(island)
     }
 
-    /// Removes an island by its Id, sending a message to all clients encouraging
-    /// them to delete it.
+    /// Removes an island by its Id, sending a message to all clients who can see it
+    /// encouraging them to delete it.
+    /// Clients whose channels are full still see it, so `Interest` will try again.
     ///
     /// Returns the island.
     fn remove_island(&mut self, ent: hecs::Entity) -> Result<PlayerIsland, hecs::ComponentError> {
-        for (_, Session { channel, .. }) in &mut self.clients_mut() {
-            comn::send_or_err(channel, comn::EntEvent::Despawn(ent.to_bits()));
+        let id = ent.to_bits();
+        for (_, Session { channel, epoch, visible, .. }) in &mut self.clients_mut() {
+            let event = comn::EntEvent::Despawn(id);
+            if visible.contains(&id)
+                && comn::send(channel, comn::WorldEvent { epoch: *epoch, event }).is_none()
+            {
+                visible.remove(&id);
+            }
         }
         let island = self.0.remove(ent);
         if let Err(e) = self.0.despawn(ent) {
@@ -145,90 +170,245 @@ impl std::ops::DerefMut for Ecs {
 struct World {
     name: String,
     ecs: Ecs,
-    last_pos_tracker: LastPosTracker,
+    interest: Interest,
+    replicator: Replicator,
+    snapshots: Snapshots,
     tick: u32,
+    tick_ms: u32,
+    /// The newest chats sent in this World, and when, for the clients who join it later
+    backlog: VecDeque<(Instant, Chat)>,
+    backlog_len: usize,
 
-    /// Temporary buffer for storing clients before removing them.
-    timed_out: Vec<hecs::Entity>,
+    /// Temporary buffer for storing clients, and why they're leaving, before removing them.
+    leaving: Vec<(hecs::Entity, String)>,
+    /// Clients who have asked to go to another World, and the name of that World.
+    travelers: Vec<(hecs::Entity, String)>,
 }
 impl World {
-    fn new(name: impl ToString) -> Self {
+    fn new(name: impl ToString, config: &Config) -> Self {
         Self {
             name: name.to_string(),
             ecs: Ecs::new(),
-            last_pos_tracker: LastPosTracker::new(),
+            interest: Interest::new(config.view_radius),
+            replicator: Replicator::new(),
+            snapshots: Snapshots::new(),
             tick: 0,
-            timed_out: Vec::with_capacity(10),
+            tick_ms: config.tick_ms,
+            backlog: VecDeque::with_capacity(config.chat_backlog),
+            backlog_len: config.chat_backlog,
+            leaving: Vec::with_capacity(10),
+            travelers: Vec::with_capacity(10),
         }
     }
 
     /// Add a client and their island to this world,
-    /// sending them an intitial WorldJoin packet with essential world state.
+    /// sending them an intitial WorldJoin packet with essential world state,
+    /// followed by the chats they missed.
     fn connect(&mut self, island: PlayerIsland) {
-        use comn::{send_or_err, WorldJoin};
-        let Self { name, ecs, tick, .. } = self;
+        use comn::{send_or_err, PastChat, WorldJoin};
+        let Self { name, ecs, tick, tick_ms, backlog, .. } = self;
 
         log::info!(
-            "{} > {} joined in! world clients: {}",
+            "{} > {} ({}) joined in! world clients: {}",
             name,
+            island.session.name,
             island.session.addr,
             ecs.client_count() + 1
         );
 
+        let pos = island.pos;
         let ent = ecs.add_island(island);
-        let islands =
-            ecs.query::<(&_, &_)>().iter().map(|(e, (&p, &a))| (e.to_bits(), p, a)).collect();
+        let mut comps = Vec::with_capacity(4);
+        comn::gather(ecs, ent, &mut comps);
+        let islands = vec![(ent.to_bits(), pos, comps)];
 
+        // everything else they can see will be spawned for them by Interest
+        let mut session = ecs.get_mut::<Session>(ent).unwrap();
+        session.visible.insert(ent.to_bits());
+        let epoch = session.epoch;
         send_or_err(
-            &mut ecs.get_mut::<Session>(ent).unwrap().channel,
+            &mut session.channel,
             WorldJoin {
+                epoch,
                 world_name: name.clone(),
                 islands,
                 your_island: ent.to_bits(),
                 tick: *tick,
+                tick_ms: *tick_ms,
             },
         );
+        for (said_at, chat) in backlog.iter() {
+            let age_secs = said_at.elapsed().as_secs() as u32;
+            send_or_err(&mut session.channel, PastChat { age_secs, chat: chat.clone() });
+        }
+    }
+
+    /// Moves everything in the world forward by a tick.
+    fn simulate(&mut self) {
+        let Self { ecs, tick, tick_ms, .. } = self;
+        // positions are recorded after the tick is moved on, see `World::update`
+        steer(ecs, *tick + 1, *tick_ms);
+        acknowledge(ecs);
+        revolve(ecs, *tick, *tick_ms);
     }
 
     fn update(&mut self, chat: &mut ChatDispatcher) {
-        let Self { last_pos_tracker, ecs, timed_out, name, tick, .. } = self;
+        let Self {
+            interest,
+            replicator,
+            snapshots,
+            ecs,
+            leaving,
+            travelers,
+            name,
+            tick,
+            backlog,
+            backlog_len,
+            ..
+        } = self;
         *tick += 1;
 
-        last_pos_tracker.track(ecs, *tick);
-        for (e, client) in &mut ecs.clients_mut() {
-            if client.heartbeat() {
-                timed_out.push(e);
+        for said in chat.world_chats(name) {
+            backlog.push_back((Instant::now(), said.clone()));
+        }
+        while backlog.len() > *backlog_len {
+            backlog.pop_front();
+        }
+
+        interest.index(ecs);
+        replicator.track(ecs);
+        snapshots.take(ecs, *tick);
+        for (e, (client, &pos)) in &mut ecs.query::<(&mut Session, &Vec2)>() {
+            if let Some(reason) = client.departure() {
+                leaving.push((e, reason));
             }
-            last_pos_tracker.sync(client);
-            chat.sync(client);
+            while let Some(comn::TravelTo(destination)) = client.channel.recv() {
+                travelers.push((e, destination));
+            }
+            client.ping();
+            client.tell_time(*tick);
+            interest.update(ecs, *tick, client, pos);
+            replicator.sync(client);
+            snapshots.sync(client);
+            chat.sync(name, client);
             client.channel.flush_all();
         }
 
-        for timed_out in timed_out.drain(..) {
-            let island = ecs.remove_island(timed_out).unwrap();
+        for (ent, reason) in leaving.drain(..) {
+            let island = ecs.remove_island(ent).unwrap();
             log::info!(
-                "{} > {} timed out! world clients: {}",
+                "{} > {} left ({})! world clients: {}",
                 name,
-                island.session.addr,
+                island.session.name,
+                reason,
                 ecs.client_count()
             );
         }
     }
 
+    /// Removes a client's island from this world so that they can be put in another,
+    /// returning their Session.
+    fn leave(&mut self, ent: hecs::Entity, destination: &str) -> Option<Session> {
+        let Self { ecs, name, .. } = self;
+        match ecs.remove_island(ent) {
+            Ok(PlayerIsland { mut session, .. }) => {
+                log::info!(
+                    "{} > {} left for {}! world clients: {}",
+                    name,
+                    session.name,
+                    destination,
+                    ecs.client_count()
+                );
+                session.leave_world();
+                Some(session)
+            }
+            Err(e) => {
+                log::error!("couldn't remove {:?} from {}: {}", ent, name, e);
+                None
+            }
+        }
+    }
+
+    /// Removes a client's island from this world, telling them why they were removed.
+    fn kick(&mut self, ent: hecs::Entity, reason: &str, farewells: &mut Farewells) {
+        let Self { ecs, name, .. } = self;
+        match ecs.remove_island(ent) {
+            Ok(mut island) => {
+                log::info!(
+                    "{} > {} kicked ({})! world clients: {}",
+                    name,
+                    island.session.name,
+                    reason,
+                    ecs.client_count()
+                );
+                island.session.kick(reason);
+                farewells.add(island.session);
+            }
+            Err(e) => log::error!("couldn't kick {:?}: {}", ent, e),
+        }
+    }
+
+    /// Kicks every client out of this world, see `World::kick`.
+    fn kick_all(&mut self, reason: &str, farewells: &mut Farewells) {
+        let clients: Vec<hecs::Entity> = self.ecs.clients().iter().map(|(e, _)| e).collect();
+        for ent in clients {
+            self.kick(ent, reason, farewells);
+        }
+    }
+
     /// Returns `true` if any clients are connected
     fn is_occupied(&self) -> bool {
-        self.ecs.client_count() > 0
+        self.client_count() > 0
+    }
+
+    fn client_count(&self) -> usize {
+        self.ecs.client_count()
     }
 
     /// Removes all islands, etc. without notifying any collected clients.
     /// Use with caution.
     fn clear(&mut self) {
         self.ecs.clear();
+        self.backlog.clear();
+    }
+
+    /// Captures everything but the player islands, which leave with their clients.
+    fn save(&self) -> WorldSave {
+        let ents = self
+            .ecs
+            .query::<&Vec2>()
+            .without::<Session>()
+            .iter()
+            .map(|(ent, &pos)| {
+                let mut comps = Vec::with_capacity(4);
+                comn::gather(&self.ecs, ent, &mut comps);
+                let revolve = self.ecs.get::<Revolve>(ent).ok().map(|r| *r);
+                EntSave { pos, comps, revolve }
+            })
+            .collect();
+
+        WorldSave::new(self.name.clone(), self.tick, ents)
+    }
+
+    /// Replaces everything in this world with what's in the save.
+    /// Must not be called while clients are connected, see `World::clear`.
+    fn restore(&mut self, save: WorldSave) {
+        self.clear();
+        self.tick = save.tick;
+        for EntSave { pos, comps, revolve } in save.ents {
+            let ent = self.ecs.spawn((pos,));
+            for comp in comps {
+                comn::insert(&mut self.ecs, ent, comp).unwrap();
+            }
+            if let Some(revolve) = revolve {
+                self.ecs.insert_one(ent, revolve).unwrap();
+            }
+        }
     }
 }
 
 use std::time::Instant;
-#[derive(Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq)]
 struct Revolve {
     center: Vec2,
     offset: f32,
@@ -243,10 +423,12 @@ impl Revolve {
     }
 }
 
-fn revolve(ecs: &mut hecs::World, tick: u32) {
+fn revolve(ecs: &mut hecs::World, tick: u32, tick_ms: u32) {
     for (_, (pos, &Revolve { center, offset })) in &mut ecs.query::<(&mut Vec2, &_)>() {
         let dist = (center - *pos).length();
-        let t = (tick * comn::SERVER_TICK_MS) as f32 / 1000.0 as f32 + offset;
+        // in f64 and wrapped to a single turn, since ticks * milliseconds soon outgrow a u32
+        let turned = (tick as f64 * tick_ms as f64 / 1000.0) % std::f64::consts::TAU;
+        let t = turned as f32 + offset;
         *pos = center + dist * comn::angle_to_vec(t);
     }
 }
@@ -256,26 +438,35 @@ fn prepare_starter(world: &mut World) {
     const MAX: usize = 1;
     for i in 0..MAX {
         use std::f32::consts::TAU;
+        let center = Vec2::zero();
         world.ecs.spawn((
             Vec2::one(),
             comn::Art::Vase,
-            Revolve::offset(Vec2::zero(), i as f32 / MAX as f32 * TAU),
+            comn::Interp::Angular { center },
+            Revolve::offset(center, i as f32 / MAX as f32 * TAU),
         ));
     }
 }
 
 struct StarterWorlds {
     worlds: Vec<World>,
+    config: Config,
 }
 impl StarterWorlds {
-    fn new() -> Self {
-        Self { worlds: Vec::with_capacity(10) }
+    fn new(config: &Config) -> Self {
+        Self { worlds: Vec::with_capacity(10), config: config.clone() }
     }
 
-    /// Connects a client to a Starter World, reusing an old one if
-    /// an empty one is available and allocating a new one otherwise.
+    /// Connects a client to a Starter World, preferring one that already has players
+    /// but still has room for more, then reusing an old one if an empty one is available
+    /// and allocating a new one otherwise.
     fn connect(&mut self, client: Session) {
         let island = PlayerIsland::new(Vec2::zero(), client);
+        let capacity = self.config.max_clients_per_world;
+        if let Some(world) = self.occupied_mut().find(|w| w.client_count() < capacity) {
+            world.connect(island);
+            return;
+        }
         if let Some(world) = self.unoccupied_mut().next() {
             prepare_starter(world);
             world.connect(island);
@@ -283,19 +474,13 @@ impl StarterWorlds {
         }
 
         let worlds = &mut self.worlds;
-        let mut new_world = World::new(format!("Starter World {}", worlds.len()));
+        let name = format!("Starter World {}", worlds.len());
+        let mut new_world = World::new(name, &self.config);
         prepare_starter(&mut new_world);
         new_world.connect(island);
         worlds.push(new_world);
     }
 
-    fn update(&mut self, chat: &mut ChatDispatcher) {
-        for world in &mut self.worlds {
-            revolve(&mut world.ecs, world.tick);
-            world.update(chat);
-        }
-    }
-
     /// Returns an iterator over mutable references to the worlds
     /// that have clients in them.
     pub fn occupied_mut(&mut self) -> impl Iterator<Item = &mut World> {
@@ -309,32 +494,591 @@ impl StarterWorlds {
     }
 }
 
-/// Technically a Quadratic Bezier Curve.
-/// that won't stop me from calling it a "three_lerp" or a thlerp for short :)
-fn thlerp(p0: Vec2, p1: Vec2, p2: Vec2, t: f32) -> Vec2 {
-    p0.lerp(p1, t).lerp(p1.lerp(p2, t), t)
+/// Every World on the server; both the persistent named ones, which stick around
+/// when nobody is in them, and the throwaway Starter Worlds that new clients are put in.
+struct WorldRegistry {
+    named: Vec<World>,
+    starters: StarterWorlds,
+    max_clients: usize,
+    save_dir: String,
+    /// Saves still being written to disk, see `WorldRegistry::save`
+    writing: Option<smol::Task<()>>,
+}
+impl WorldRegistry {
+    /// Loads each named World from its save, preparing a fresh one if it has never been saved.
+    /// A save that can't be read is an Err, rather than a fresh World that would overwrite it.
+    fn new(config: &Config) -> Result<Self, String> {
+        let mut named = Vec::with_capacity(config.worlds.len());
+        for name in &config.worlds {
+            let mut world = World::new(name, config);
+            match save::read(&config.save_dir, name)? {
+                Some(save) => {
+                    log::info!(
+                        "{} > loaded {} things at tick {}",
+                        name,
+                        save.ents.len(),
+                        save.tick
+                    );
+                    world.restore(save);
+                }
+                None => prepare_starter(&mut world),
+            }
+            named.push(world);
+        }
+
+        Ok(Self {
+            named,
+            starters: StarterWorlds::new(config),
+            max_clients: config.world_capacity,
+            save_dir: config.save_dir.clone(),
+            writing: None,
+        })
+    }
+
+    /// Captures each named World as it is now, then writes them to disk without holding up
+    /// the tick, once any earlier saves are done. Starter Worlds are thrown away instead.
+    fn save(&mut self) {
+        let saves: Vec<WorldSave> = self.named.iter().map(World::save).collect();
+        let dir = self.save_dir.clone();
+        let earlier = self.writing.take();
+        self.writing = Some(smol::spawn(async move {
+            if let Some(earlier) = earlier {
+                earlier.await;
+            }
+            smol::unblock(move || {
+                for save in saves {
+                    if let Err(e) = save::write(&dir, &save) {
+                        log::error!("{} > couldn't save: {}", save.name, e);
+                    }
+                }
+            })
+            .await
+        }));
+    }
+
+    /// Waits until every save has been written to disk.
+    async fn saved(&mut self) {
+        if let Some(writing) = self.writing.take() {
+            writing.await;
+        }
+    }
+
+    /// New clients are put in a Starter World, from which they're free to travel.
+    fn connect(&mut self, client: Session) {
+        self.starters.connect(client);
+    }
+
+    fn worlds(&self) -> impl Iterator<Item = &World> {
+        self.named.iter().chain(self.starters.worlds.iter())
+    }
+
+    fn worlds_mut(&mut self) -> impl Iterator<Item = &mut World> {
+        self.named.iter_mut().chain(self.starters.worlds.iter_mut())
+    }
+
+    fn find_mut(&mut self, name: &str) -> Option<&mut World> {
+        self.worlds_mut().find(|world| world.name == name)
+    }
+
+    /// Returns an iterator over mutable references to the worlds
+    /// that have clients in them.
+    fn occupied_mut(&mut self) -> impl Iterator<Item = &mut World> {
+        self.worlds_mut().filter(|world| world.is_occupied())
+    }
+
+    fn update(&mut self, chat: &mut ChatDispatcher) {
+        for world in self.worlds_mut() {
+            world.simulate();
+            world.update(chat);
+        }
+        self.travel();
+    }
+
+    /// Moves each client who has asked to travel into the World they asked for,
+    /// or tells them why they can't go there.
+    fn travel(&mut self) {
+        let mut requests = Vec::new();
+        for world in self.worlds_mut() {
+            for (ent, destination) in world.travelers.drain(..) {
+                requests.push((world.name.clone(), ent, destination));
+            }
+        }
+
+        for (from, ent, to) in requests {
+            if let Err(refusal) = self.transfer(&from, ent, &to) {
+                let source = self.find_mut(&from);
+                if let Some(mut session) = source.and_then(|w| w.ecs.get_mut::<Session>(ent).ok()) {
+                    comn::send_or_err(&mut session.channel, Chat::system(refusal));
+                }
+            }
+        }
+    }
+
+    /// Moves a client's island from one World into another,
+    /// or returns why it can't be moved there.
+    fn transfer(&mut self, from: &str, ent: hecs::Entity, to: &str) -> Result<(), String> {
+        let capacity = if self.named.iter().any(|world| world.name == to) {
+            self.max_clients
+        } else {
+            self.starters.config.max_clients_per_world
+        };
+        match self.worlds().find(|world| world.name == to) {
+            None => return Err(format!("There's no world named {:?}.", to)),
+            Some(_) if from == to => return Err(format!("Already in {}!", to)),
+            Some(world) if world.client_count() >= capacity => {
+                return Err(format!("{} is full.", to))
+            }
+            Some(_) => {}
+        }
+
+        let session = self
+            .find_mut(from)
+            .and_then(|source| source.leave(ent, to))
+            .ok_or_else(|| format!("Couldn't leave {}.", from))?;
+        // it was there a moment ago
+        let destination = self.find_mut(to).unwrap();
+        destination.connect(PlayerIsland::new(Vec2::zero(), session));
+        Ok(())
+    }
+
+    fn is_online(&self, name: &str) -> bool {
+        self.worlds().any(|world| world.ecs.clients().iter().any(|(_, s)| s.name == name))
+    }
+
+    /// Finds the World a client is in by their name, along with their island.
+    fn find_client(&mut self, name: &str) -> Option<(&mut World, hecs::Entity)> {
+        self.worlds_mut().find_map(|world| {
+            let ent = world.ecs.clients().iter().find(|(_, s)| s.name == name).map(|(e, _)| e)?;
+            Some((world, ent))
+        })
+    }
+}
+
+/// Spawns a thread which forwards lines typed into the server's terminal.
+fn console() -> std::sync::mpsc::Receiver<String> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        use std::io::BufRead;
+        for line in std::io::stdin().lock().lines().filter_map(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+/// Everything the server keeps track of from one tick to the next,
+/// apart from the sockets clients connect through, which feed it new Sessions.
+struct Server {
+    config: Config,
+    chat: ChatDispatcher,
+    farewells: Farewells,
+    handshakes: Handshakes,
+    commands: Commands,
+    worlds: WorldRegistry,
+    last_save: Instant,
+    schedule: Scheduler,
+    metrics: Metrics,
 }
+impl Server {
+    fn new(config: Config) -> Result<Self, String> {
+        Ok(Self {
+            chat: ChatDispatcher::new(&config),
+            farewells: Farewells::new(),
+            handshakes: Handshakes::new(&config),
+            commands: Commands::new(),
+            worlds: WorldRegistry::new(&config)
+                .map_err(|e| format!("couldn't load worlds: {}", e))?,
+            last_save: Instant::now(),
+            schedule: Scheduler::new(config.tick(), config.overrun),
+            metrics: Metrics::new(),
+            config,
+        })
+    }
+
+    /// A newly connected client, who must shake hands before they're let into a world.
+    fn add(&mut self, session: Session) {
+        self.handshakes.add(session);
+    }
+
+    /// Handles a line typed into the server's terminal,
+    /// returning false if it asked for the server to be stopped.
+    fn console(&mut self, line: &str) -> bool {
+        let Self { chat, farewells, commands, worlds, last_save, schedule, .. } = self;
+        match line.trim() {
+            "stop" => return false,
+            "save" => {
+                worlds.save();
+                *last_save = Instant::now();
+            }
+            "ticks" => log::info!("{:#?}", schedule.stats),
+            // anything else is run like a client's slash command, see commands.rs
+            other => commands.run(
+                Invocation { caller: Caller::Console, line: other.to_string() },
+                worlds,
+                chat,
+                farewells,
+            ),
+        }
+        true
+    }
+
+    /// Moves everything on the server forward by a tick.
+    fn tick(&mut self) {
+        let Self {
+            config,
+            chat,
+            farewells,
+            handshakes,
+            commands,
+            worlds,
+            last_save,
+            schedule,
+            metrics,
+        } = self;
+
+        let accepted: Vec<Session> = handshakes.accepted(&|name| worlds.is_online(name)).collect();
+        for session in accepted {
+            worlds.connect(session);
+        }
 
-async fn start() {
-    let mut chat = ChatDispatcher::new();
-    let mut starter_worlds = StarterWorlds::new();
+        chat.clear();
+        for world in worlds.occupied_mut() {
+            chat.fill(&world.name, world.ecs.clients_mut().iter().map(|(_, s)| s));
+        }
+        chat.route();
+        for invocation in chat.take_commands() {
+            commands.run(invocation, worlds, chat, farewells);
+        }
+        worlds.update(chat);
+        farewells.prune();
+
+        if last_save.elapsed() >= config.autosave() {
+            worlds.save();
+            *last_save = Instant::now();
+        }
+        metrics.update(worlds, &schedule.stats);
+    }
+
+    /// Saves the persistent worlds and sends everyone away,
+    /// returning once they've had a chance to hear why.
+    async fn shut_down(mut self) {
+        let Self { farewells, worlds, .. } = &mut self;
+        log::info!("shutting down");
+        worlds.save();
+        worlds.saved().await;
+        for world in worlds.occupied_mut() {
+            world.kick_all("server shutting down", farewells);
+        }
+        while !farewells.is_empty() {
+            farewells.prune();
+            smol::Timer::after(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+async fn start(config: Config) {
+    let console = console();
+    let mut server = match Server::new(config.clone()) {
+        Ok(server) => server,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
     let (client_tx, client_rx) = std::sync::mpsc::sync_channel(100);
 
-    smol::spawn(open_socket(comn::SERVER, 2500, client_tx)).detach();
+    log::info!("listening on {}", config.bind);
+    if !config.link.is_perfect() {
+        log::warn!("simulating a bad connection to every client: {:?}", config.link);
+    }
+    let socket = open_socket(
+        config.bind.clone(),
+        config.pool_size,
+        config.heartbeat_timeout(),
+        config.link,
+        client_tx,
+    );
+    smol::spawn(socket).detach();
+    if !config.metrics_bind.is_empty() {
+        smol::spawn(metrics::serve(config.metrics_bind.clone(), server.metrics.text())).detach();
+    }
 
-    let mut step_time = Instant::now();
     loop {
-        // Add any new clients to our collection of channels
+        let started = Instant::now();
+        if let Ok(line) = console.try_recv() {
+            if !server.console(&line) {
+                break;
+            }
+        }
         if let Ok(session) = client_rx.try_recv() {
-            starter_worlds.connect(session);
+            server.add(session);
         }
+        server.tick();
+
+        smol::Timer::at(server.schedule.finish(started)).await;
+    }
+    server.shut_down().await;
+}
 
-        for world in starter_worlds.occupied_mut() {
-            chat.fill(world.ecs.clients_mut().iter().map(|(_, s)| s));
+/// A Server to test against, which doesn't touch the disk or wait long between ticks.
+#[cfg(test)]
+fn test_server(tweak: impl FnOnce(&mut Config)) -> Server {
+    let mut config =
+        Config { tick_ms: 10, worlds: vec![], save_dir: String::new(), ..Default::default() };
+    tweak(&mut config);
+    Server::new(config).unwrap()
+}
+
+/// How many ticks `tick_until` waits before giving up, a few seconds' worth in a `test_server`.
+#[cfg(test)]
+const PATIENCE: u32 = 500;
+
+/// Ticks the server until `done` returns true.
+/// Panics if that takes more than `PATIENCE` ticks, however quickly or slowly those went by.
+#[cfg(test)]
+fn tick_until(server: &mut Server, mut done: impl FnMut(&mut Server) -> bool) {
+    for _ in 0..PATIENCE {
+        if done(server) {
+            return;
         }
-        starter_worlds.update(&mut chat);
+        server.tick();
+        std::thread::sleep(server.config.tick());
+    }
+    panic!("gave up waiting on the server after {} ticks", PATIENCE);
+}
 
-        step_time += Duration::from_millis(comn::SERVER_TICK_MS as _);
-        smol::Timer::at(step_time).await;
+/// Connects a client to the server over a simulated link, waiting until they're let in.
+#[cfg(test)]
+fn join(
+    server: &mut Server,
+    name: &str,
+    op_token: Option<&str>,
+    conditions: comn::link::LinkConditions,
+) -> Result<comn::headless::Client, String> {
+    // loopback Sessions still need an address of their own, and names are unique
+    let [a, b, c, d] = fxhash::hash32(name).to_be_bytes();
+    let addr = ([127, a, b, c], 1024 + d as u16).into();
+    join_from(server, addr, name, op_token, conditions)
+}
+
+/// Like `join`, but from a particular address.
+#[cfg(test)]
+fn join_from(
+    server: &mut Server,
+    addr: std::net::SocketAddr,
+    name: &str,
+    op_token: Option<&str>,
+    conditions: comn::link::LinkConditions,
+) -> Result<comn::headless::Client, String> {
+    use comn::headless::{self, Client, Heart};
+
+    let (mut channel, server_end) =
+        comn::link::loopback(conditions, fxhash::hash64(&addr), server.config.pool_size);
+    server.add(Session::new(server_end, addr, server.config.heartbeat_timeout()));
+    headless::introduce(&mut channel, name, op_token);
+
+    let mut heart = Heart::new();
+    let mut joined = None;
+    tick_until(server, |_| {
+        joined = headless::poll_join(&mut channel, &mut heart);
+        joined.is_some()
+    });
+    joined.unwrap().map(|join| Client::new(channel, heart, join))
+}
+
+/// Keeps everyone up to date until `listener` hears a Chat, which is returned.
+#[cfg(test)]
+fn hear(
+    server: &mut Server,
+    listener: &mut comn::headless::Client,
+    others: &mut [&mut comn::headless::Client],
+) -> Chat {
+    let mut heard = None;
+    tick_until(server, |_| {
+        for client in others.iter_mut() {
+            client.update(Vec2::zero());
+            client.channel.flush_all();
+        }
+        listener.update(Vec2::zero());
+        listener.channel.flush_all();
+        heard = listener.channel.recv::<Chat>();
+        heard.is_some()
+    });
+    heard.unwrap()
+}
+
+#[cfg(test)]
+fn bad_link() -> comn::link::LinkConditions {
+    comn::link::LinkConditions {
+        latency_ms: 20,
+        jitter_ms: 10,
+        loss: 0.1,
+        duplicate: 0.05,
+        reorder: 0.1,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn join_and_move() {
+    let mut server = test_server(|_| {});
+    let mut client = join(&mut server, "sailor", None, bad_link()).unwrap();
+
+    tick_until(&mut server, |server| {
+        client.update(glam::vec2(1.0, 0.0));
+        client.channel.flush_all();
+
+        let (world, ent) = server.worlds.find_client("sailor").unwrap();
+        let x = world.ecs.get::<Vec2>(ent).unwrap().x();
+        x > 1.0
+    });
+}
+
+#[test]
+fn names_are_unique() {
+    let mut server = test_server(|_| {});
+    let _first = join(&mut server, "sailor", None, bad_link()).unwrap();
+
+    let rejection = join(&mut server, "sailor", None, bad_link()).err().unwrap();
+    assert!(rejection.contains("taken"), "rejected for {:?}", rejection);
+}
+
+#[test]
+fn chat_in_world() {
+    let mut server = test_server(|config| config.max_clients_per_world = 2);
+    let mut alice = join(&mut server, "alice", None, bad_link()).unwrap();
+    let mut bob = join(&mut server, "bob", None, bad_link()).unwrap();
+
+    comn::send_or_err(&mut alice.channel, Chat::new(comn::ChatScope::World, "ahoy"));
+    let mut heard = None;
+    tick_until(&mut server, |_| {
+        for client in &mut [&mut alice, &mut bob] {
+            client.update(Vec2::zero());
+            client.channel.flush_all();
+        }
+        heard = heard.take().or_else(|| bob.channel.recv::<Chat>());
+        heard.is_some()
+    });
+
+    let Chat { from, text, .. } = heard.unwrap();
+    assert_eq!((from.as_str(), text.as_str()), ("alice", "ahoy"));
+}
+
+#[test]
+fn silent_clients_time_out() {
+    let mut server = test_server(|config| config.heartbeat_timeout_secs = 0.3);
+    let _client = join(&mut server, "sailor", None, bad_link()).unwrap();
+
+    assert!(server.worlds.is_online("sailor"));
+
+    // without any updates, the client never sends another Heartbeat
+    tick_until(&mut server, |server| !server.worlds.is_online("sailor"));
+}
+
+#[test]
+fn goodbyes_are_heard() {
+    // timing out takes far longer than the test is willing to wait, so it can't pass that way
+    let mut server = test_server(|config| config.heartbeat_timeout_secs = 60.0);
+    let mut client = join(&mut server, "sailor", None, bad_link()).unwrap();
+
+    client.leave("off to bed");
+    tick_until(&mut server, |server| !server.worlds.is_online("sailor"));
+}
+
+#[test]
+fn ops_need_a_token() {
+    let mut server = test_server(|config| {
+        config.op_tokens = vec!["hunter2".to_string()];
+        config.max_clients_per_world = 2;
+    });
+    let rejection = join(&mut server, "mallory", Some("guess"), bad_link()).err().unwrap();
+    assert!(rejection.contains("op token"), "rejected for {:?}", rejection);
+
+    // going by the name of an op doesn't make you one
+    let mut captain = join(&mut server, "captain", None, bad_link()).unwrap();
+    let mut sailor = join(&mut server, "sailor", Some("hunter2"), bad_link()).unwrap();
+
+    comn::send_or_err(&mut captain.channel, Chat::new(comn::ChatScope::World, "/kick sailor"));
+    let told = hear(&mut server, &mut captain, &mut [&mut sailor]);
+    assert!(told.text.contains("aren't allowed"), "told {:?}", told.text);
+
+    comn::send_or_err(&mut sailor.channel, Chat::new(comn::ChatScope::World, "/kick captain"));
+    tick_until(&mut server, |server| {
+        for client in &mut [&mut captain, &mut sailor] {
+            client.update(Vec2::zero());
+            client.channel.flush_all();
+        }
+        !server.worlds.is_online("captain")
+    });
+}
+
+#[test]
+fn mutes_outlast_names() {
+    let mut server = test_server(|config| {
+        config.op_tokens = vec!["hunter2".to_string()];
+        config.max_clients_per_world = 2;
+    });
+    let mut captain = join(&mut server, "captain", Some("hunter2"), bad_link()).unwrap();
+    let troll_addr = ([10, 0, 0, 7], 7).into();
+    let mut troll = join_from(&mut server, troll_addr, "troll", None, bad_link()).unwrap();
+
+    comn::send_or_err(&mut captain.channel, Chat::new(comn::ChatScope::World, "/mute troll"));
+    let told = hear(&mut server, &mut captain, &mut [&mut troll]);
+    assert!(told.text.starts_with("Muted troll"), "told {:?}", told.text);
+
+    troll.leave("brb");
+    tick_until(&mut server, |server| !server.worlds.is_online("troll"));
+
+    // a new name doesn't get them a new voice
+    let mut troll = join_from(&mut server, troll_addr, "not_a_troll", None, bad_link()).unwrap();
+    comn::send_or_err(&mut troll.channel, Chat::new(comn::ChatScope::World, "ahoy"));
+    let told = hear(&mut server, &mut troll, &mut [&mut captain]);
+    assert_eq!(told.text, "You're muted.");
+}
+
+/// A save directory of its own for a test, which is emptied before it's used.
+#[cfg(test)]
+fn test_save_dir(test: &str) -> String {
+    let dir = std::env::temp_dir().join(format!("server-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir.to_string_lossy().into_owned()
+}
+
+#[test]
+fn worlds_are_saved_and_loaded() {
+    let save_dir = test_save_dir("saves");
+    let configure = |config: &mut Config| {
+        config.worlds = vec!["Hub".to_string()];
+        config.save_dir = save_dir.clone();
+    };
+    let mut server = test_server(configure);
+    for _ in 0..5 {
+        server.tick();
     }
+    server.worlds.save();
+    smol::block_on(server.worlds.saved());
+
+    let hub = |server: &Server| server.worlds.named[0].save();
+    let loaded = test_server(configure);
+    assert_eq!(hub(&loaded), hub(&server));
+    assert_eq!(hub(&loaded).tick, 5);
+    let _ = std::fs::remove_dir_all(&save_dir);
+}
+
+#[test]
+fn unreadable_saves_stop_the_server() {
+    let save_dir = test_save_dir("unreadable");
+    std::fs::create_dir_all(&save_dir).unwrap();
+    std::fs::write(std::path::Path::new(&save_dir).join("Hub.ron"), "(version: 1, oops").unwrap();
+
+    let config = Config {
+        worlds: vec!["Hub".to_string()],
+        save_dir: save_dir.clone(),
+        ..Default::default()
+    };
+    let refusal = Server::new(config).err().unwrap();
+    assert!(refusal.contains("Hub.ron"), "refused with {:?}", refusal);
+    let _ = std::fs::remove_dir_all(&save_dir);
 }
@@ -0,0 +1,168 @@
+use crate::{net::TIMEOUTS, schedule::TickStats, WorldRegistry};
+use std::{
+    fmt::Display,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How often the metrics are rendered, anyone asking in between gets the previous rendering.
+const RENDER_EVERY: Duration = Duration::from_secs(1);
+
+/// Keeps a rendering of how the server is doing in the Prometheus text format,
+/// for `serve` to hand out.
+pub struct Metrics {
+    text: Arc<Mutex<String>>,
+    rendered: Instant,
+}
+impl Metrics {
+    pub fn new() -> Self {
+        Self { text: Arc::new(Mutex::new(String::new())), rendered: Instant::now() - RENDER_EVERY }
+    }
+
+    /// Where the newest rendering is kept.
+    pub fn text(&self) -> Arc<Mutex<String>> {
+        self.text.clone()
+    }
+
+    /// Renders the metrics again, if it's been long enough since the last time.
+    pub fn update(&mut self, worlds: &WorldRegistry, ticks: &TickStats) {
+        if self.rendered.elapsed() >= RENDER_EVERY {
+            self.rendered = Instant::now();
+            let text = render(worlds, ticks);
+            *self.text.lock().unwrap() = text;
+        }
+    }
+}
+
+/// Builds up a page of metrics in the Prometheus text format.
+struct Page(String);
+impl Page {
+    /// Describes the samples that follow, which must all be named `name`.
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        self.0.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, v)| {
+                format!("{}=\"{}\"", label, v.replace('\\', "\\\\").replace('"', "\\\""))
+            })
+            .collect();
+        if labels.is_empty() {
+            self.0.push_str(&format!("{} {}\n", name, value));
+        } else {
+            self.0.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value));
+        }
+    }
+}
+
+fn render(worlds: &WorldRegistry, ticks: &TickStats) -> String {
+    let mut page = Page(String::with_capacity(4096));
+
+    page.family("server_ticks_total", "counter", "Ticks run since the server started.");
+    page.sample("server_ticks_total", &[], ticks.ticks);
+    page.family("server_tick_overruns_total", "counter", "Ticks that took too long.");
+    page.sample("server_tick_overruns_total", &[], ticks.overruns);
+    page.family("server_ticks_skipped_total", "counter", "Ticks dropped to get back on schedule.");
+    page.sample("server_ticks_skipped_total", &[], ticks.skipped);
+    page.family("server_tick_seconds", "gauge", "How long ticks have been taking.");
+    for (stat, took) in
+        &[("last", ticks.last), ("average", ticks.average), ("longest", ticks.longest)]
+    {
+        page.sample("server_tick_seconds", &[("stat", *stat)], took.as_secs_f64());
+    }
+    page.family("server_behind_seconds", "gauge", "How far behind schedule the server is.");
+    page.sample("server_behind_seconds", &[], ticks.behind.as_secs_f64());
+
+    page.family("server_world_clients", "gauge", "Clients in each World.");
+    for world in worlds.worlds() {
+        page.sample(
+            "server_world_clients",
+            &[("world", world.name.as_str())],
+            world.client_count(),
+        );
+    }
+    page.family("server_world_tick", "gauge", "The tick each World is on.");
+    for world in worlds.worlds() {
+        page.sample("server_world_tick", &[("world", world.name.as_str())], world.tick);
+    }
+    page.family(
+        "server_session_rtt_seconds",
+        "gauge",
+        "How long a round trip to each client takes.",
+    );
+    for world in worlds.worlds() {
+        for (_, session) in world.ecs.clients().iter() {
+            if let Some(rtt) = session.rtt {
+                let labels = [("world", world.name.as_str()), ("name", session.name.as_str())];
+                page.sample("server_session_rtt_seconds", &labels, rtt.as_secs_f64());
+            }
+        }
+    }
+    page.family("server_timeouts_total", "counter", "Clients booted for going quiet.");
+    page.sample("server_timeouts_total", &[], TIMEOUTS.load(Ordering::Relaxed));
+
+    let traffic = comn::traffic();
+    let families: [(&str, &str, fn(&comn::Traffic) -> u64); 6] = [
+        ("server_messages_sent_total", "Messages sent, by type.", |t| t.messages),
+        ("server_sends_rejected_total", "Messages that couldn't be sent, by type.", |t| t.rejected),
+        ("server_packets_sent_total", "Packets sent, by the type of message in them.", |t| {
+            t.packets
+        }),
+        ("server_bytes_sent_total", "Bytes sent, by the type of message they hold.", |t| t.bytes),
+        (
+            "server_packets_received_total",
+            "Packets received, by the type of message in them.",
+            |t| t.packets_received,
+        ),
+        ("server_bytes_received_total", "Bytes received, by the type of message they hold.", |t| {
+            t.bytes_received
+        }),
+    ];
+    for (name, help, count) in &families {
+        page.family(name, "counter", help);
+        for t in &traffic {
+            page.sample(name, &[("message", t.name)], count(t));
+        }
+    }
+
+    page.0
+}
+
+/// Answers every connection to `addr` with the newest metrics, over HTTP.
+/// Whatever was asked for, the metrics are the only thing there is to give.
+pub async fn serve(addr: String, text: Arc<Mutex<String>>) {
+    use smol::io::{AsyncReadExt, AsyncWriteExt};
+
+    let listener = match smol::net::TcpListener::bind(&addr).await {
+        Ok(listener) => listener,
+        Err(e) => return log::error!("couldn't serve metrics on {}: {}", addr, e),
+    };
+    log::info!("serving metrics on {}", addr);
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                log::error!("couldn't accept metrics connection: {}", e);
+                continue;
+            }
+        };
+        let text = text.lock().unwrap().clone();
+        smol::spawn(async move {
+            let mut request = [0; 1024];
+            comn::or_err!("couldn't read metrics request: {}", stream.read(&mut request).await);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\n\
+                 Content-Type: text/plain; version=0.0.4\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                text.len(),
+                text
+            );
+            comn::or_err!("couldn't send metrics: {}", stream.write_all(response.as_bytes()).await);
+        })
+        .detach();
+    }
+}
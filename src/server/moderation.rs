@@ -0,0 +1,96 @@
+use crate::config::Config;
+use fxhash::{FxHashMap, FxHashSet};
+use std::{net::IpAddr, time::Instant};
+
+/// Lets a client send a burst of chats, but only so many per second after that.
+struct TokenBucket {
+    tokens: f32,
+    refilled: Instant,
+}
+
+/// Decides which chats are fit to be passed on.
+pub struct Moderation {
+    /// How many chats each client may send per second, once their burst is spent
+    rate: f32,
+    /// How many chats each client may send at once
+    burst: f32,
+    /// The longest a chat may be, in characters
+    max_len: usize,
+    /// Words that chats may not contain, in lowercase
+    blocklist: FxHashSet<String>,
+    /// The addresses of the clients who may not chat at all, not names, see `Config::op_tokens`
+    muted: FxHashSet<IpAddr>,
+    /// Only clients who've spent some of their burst are tracked, see `prune`
+    buckets: FxHashMap<String, TokenBucket>,
+}
+impl Moderation {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            rate: config.chat_rate,
+            burst: config.chat_burst,
+            max_len: config.chat_max_len,
+            blocklist: config.chat_blocklist.iter().map(|w| w.to_lowercase()).collect(),
+            muted: config.muted.iter().copied().collect(),
+            buckets: FxHashMap::default(),
+        }
+    }
+
+    /// Spends one of a client's tokens, or returns why they can't chat right now.
+    pub fn throttle(&mut self, name: &str) -> Result<(), String> {
+        let Self { rate, burst, buckets, .. } = self;
+        let bucket = buckets
+            .entry(name.to_string())
+            .or_insert_with(|| TokenBucket { tokens: *burst, refilled: Instant::now() });
+
+        let refill = bucket.refilled.elapsed().as_secs_f32() * *rate;
+        bucket.tokens = (bucket.tokens + refill).min(*burst);
+        bucket.refilled = Instant::now();
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err("You're sending messages too quickly, slow down!".to_string())
+        }
+    }
+
+    /// Returns why a chat from the client at `addr` shouldn't be passed on, if it shouldn't.
+    pub fn check(&self, addr: IpAddr, text: &str) -> Result<(), String> {
+        if self.muted.contains(&addr) {
+            return Err("You're muted.".to_string());
+        }
+
+        let len = text.chars().count();
+        if len > self.max_len {
+            return Err(format!(
+                "Your message is {} characters long, the most allowed is {}.",
+                len, self.max_len
+            ));
+        }
+
+        let blocked = text
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|word| self.blocklist.contains(&word.to_lowercase()));
+        if blocked {
+            return Err("Your message contains a blocked word.".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Returns false if they were already muted.
+    pub fn mute(&mut self, addr: IpAddr) -> bool {
+        self.muted.insert(addr)
+    }
+
+    /// Returns false if they weren't muted.
+    pub fn unmute(&mut self, addr: IpAddr) -> bool {
+        self.muted.remove(&addr)
+    }
+
+    /// Forgets the buckets that have filled back up, since they're the same as new ones.
+    pub fn prune(&mut self) {
+        let Self { rate, burst, buckets, .. } = self;
+        buckets.retain(|_, b| b.tokens + b.refilled.elapsed().as_secs_f32() * *rate < *burst);
+    }
+}
@@ -1,35 +1,291 @@
-use comn::Heartbeat;
-use std::{net::SocketAddr, sync::mpsc::SyncSender, time::Instant};
+use comn::{
+    link::LinkConditions, send_or_err, Disconnect, Handshake, HandshakeReply, Heartbeat,
+    Introduction, Ping, Pong, TimeReply, TimeRequest,
+};
+use fxhash::FxHashSet;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::SyncSender,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often each client in a World is sent a Ping.
+const PING_EVERY: Duration = Duration::from_secs(1);
+
+/// How many clients have been booted for going quiet, since the server started.
+pub static TIMEOUTS: AtomicU64 = AtomicU64::new(0);
 use turbulence::MessageChannels;
 
 #[derive(Debug)]
 pub struct Session {
     pub channel: MessageChannels,
     pub addr: SocketAddr,
+    /// What other clients know this client as,
+    /// which is their address until they've introduced themselves
+    pub name: String,
+    /// Whether they introduced themselves with one of the config's op tokens
+    pub op: bool,
     pub heartbeat: std::time::Instant,
+    /// How long the client can go without a Heartbeat before they've timed out
+    pub timeout: Duration,
+    /// How many Worlds the client has left, see `WorldJoin::epoch`
+    pub epoch: u32,
+    /// The tick of the newest Snapshot the client has received all of
+    pub acked_snapshot: Option<u32>,
+    /// The ids of the entities the client has been told about
+    pub visible: FxHashSet<u64>,
+    /// How long the newest round trip to the client took, once they've answered a Ping
+    pub rtt: Option<Duration>,
+    /// The id of the newest Ping sent to the client, and when it was sent
+    ping: (u32, Instant),
+    /// Whether they've answered the newest Ping, so that duplicate answers aren't counted
+    answered: bool,
 }
 impl Session {
-    pub fn new(channel: MessageChannels, addr: SocketAddr) -> Self {
-        Self { channel, addr, heartbeat: Instant::now() }
+    pub fn new(channel: MessageChannels, addr: SocketAddr, timeout: Duration) -> Self {
+        Self {
+            channel,
+            addr,
+            name: addr.to_string(),
+            op: false,
+            heartbeat: Instant::now(),
+            timeout,
+            epoch: 0,
+            acked_snapshot: None,
+            visible: FxHashSet::default(),
+            rtt: None,
+            ping: (0, Instant::now()),
+            answered: true,
+        }
+    }
+
+    /// Sends the client a Ping every so often, timing how long it takes them to answer.
+    /// Answers to older Pings are ignored, so that the RTT only reflects the newest.
+    pub fn ping(&mut self) {
+        let Self { channel, rtt, ping: (id, sent), answered, .. } = self;
+
+        while let Some(Pong(pong)) = channel.recv() {
+            if pong == *id && !*answered {
+                *rtt = Some(sent.elapsed());
+                *answered = true;
+            }
+        }
+
+        if sent.elapsed() >= PING_EVERY {
+            *id += 1;
+            *sent = Instant::now();
+            *answered = false;
+            let last_rtt_ms = rtt.map(|rtt| rtt.as_millis() as u32);
+            send_or_err(channel, Ping { id: *id, last_rtt_ms });
+        }
+    }
+
+    /// Tells the client which tick their World is on, whenever they ask.
+    pub fn tell_time(&mut self, tick: u32) {
+        let Self { channel, .. } = self;
+        while let Some(TimeRequest(id)) = channel.recv() {
+            send_or_err(channel, TimeReply { id, tick });
+        }
     }
 
     /// Returns true if the user has timed out
     pub fn heartbeat(&mut self) -> bool {
-        let Self { channel, heartbeat, .. } = self;
+        let Self { channel, heartbeat, timeout, .. } = self;
 
         // Manage client heartbeats, boot out the timeouts.
         if let Some(Heartbeat) = channel.recv() {
             *heartbeat = Instant::now();
         }
 
-        heartbeat.elapsed().as_secs_f32() > 3.0
+        heartbeat.elapsed() > *timeout
+    }
+
+    /// Forgets everything the client was told about the World they were in,
+    /// so that they can be put in another one.
+    pub fn leave_world(&mut self) {
+        self.epoch = self.epoch.wrapping_add(1);
+        self.acked_snapshot = None;
+        self.visible.clear();
+    }
+
+    /// Returns why the client is leaving, if they've said goodbye or timed out.
+    pub fn departure(&mut self) -> Option<String> {
+        if let Some(Disconnect { reason }) = self.channel.recv() {
+            return Some(reason);
+        }
+        if self.heartbeat() {
+            TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+            Some("timed out".to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Tells the client why they're being sent away.
+    /// The Session should be given to `Farewells` afterwards so that the message has time to arrive.
+    pub fn kick(&mut self, reason: impl ToString) {
+        let Self { channel, .. } = self;
+        send_or_err(channel, Disconnect { reason: reason.to_string() });
+        channel.flush::<Disconnect>();
+    }
+
+    /// Checks for the Handshake and then the Introduction from the client,
+    /// replying once it's clear whether they can join.
+    fn greet(
+        &mut self,
+        greeting: Greeting,
+        is_taken: &dyn Fn(&str) -> bool,
+        op_tokens: &FxHashSet<String>,
+    ) -> Greeting {
+        let timed_out = self.heartbeat();
+        let Self { channel, addr, name, op, .. } = self;
+
+        let verdict = match greeting {
+            Greeting::Waiting => match channel.recv::<Handshake>() {
+                Some(handshake) => handshake.mismatch().map_or(Ok(Greeting::Shaken), Err),
+                None => Ok(Greeting::Waiting),
+            },
+            Greeting::Shaken => match channel.recv::<Introduction>() {
+                Some(Introduction { name: wanted, op_token }) => match comn::Name::problem(&wanted)
+                {
+                    Some(problem) => Err(problem),
+                    None if is_taken(&wanted) => {
+                        Err(format!("the name {} is already taken", wanted))
+                    }
+                    None if op_token.as_ref().map_or(false, |t| !op_tokens.contains(t)) => {
+                        Err("that op token isn't valid".to_string())
+                    }
+                    None => {
+                        *name = wanted;
+                        *op = op_token.is_some();
+                        if *op {
+                            log::info!("{} is an op", name);
+                        }
+                        Ok(Greeting::Accepted)
+                    }
+                },
+                None => Ok(Greeting::Shaken),
+            },
+            other => Ok(other),
+        };
+
+        let (reply, greeting) = match verdict {
+            Ok(Greeting::Accepted) => (HandshakeReply::Accepted, Greeting::Accepted),
+            Ok(_) if timed_out => {
+                log::info!("{} timed out before shaking hands", addr);
+                return Greeting::Rejected(Instant::now());
+            }
+            Ok(waiting) => return waiting,
+            Err(reason) => {
+                log::info!("{} rejected: {}", addr, reason);
+                (HandshakeReply::Rejected(reason), Greeting::Rejected(Instant::now()))
+            }
+        };
+        send_or_err(channel, reply);
+        channel.flush::<HandshakeReply>();
+        greeting
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Greeting {
+    Waiting,
+    /// Their Handshake checks out, but they've yet to introduce themselves
+    Shaken,
+    Accepted,
+    /// When they were rejected, so they can be kept around long enough to hear why.
+    Rejected(Instant),
+}
+
+/// Sessions which have connected, but haven't yet proven that they speak our protocol.
+pub struct Handshakes {
+    pending: Vec<(Session, Greeting)>,
+    /// Clients who introduce themselves with one of these are made ops
+    op_tokens: FxHashSet<String>,
+}
+impl Handshakes {
+    pub fn new(config: &crate::config::Config) -> Self {
+        Self {
+            pending: Vec::with_capacity(10),
+            op_tokens: config.op_tokens.iter().cloned().collect(),
+        }
+    }
+
+    pub fn add(&mut self, session: Session) {
+        self.pending.push((session, Greeting::Waiting));
+    }
+
+    /// Processes any Handshakes and Introductions that have arrived,
+    /// returning the sessions that were accepted.
+    /// Mismatched sessions and those who asked for a name that `is_taken`
+    /// are told why they were rejected, then dropped a second later.
+    pub fn accepted(
+        &mut self,
+        is_taken: &dyn Fn(&str) -> bool,
+    ) -> impl Iterator<Item = Session> + '_ {
+        let mut accepted = FxHashSet::default();
+        let Self { pending, op_tokens } = self;
+        for (session, greeting) in pending.iter_mut() {
+            if let Greeting::Waiting | Greeting::Shaken = greeting {
+                // two people might ask for the same name in the same tick
+                let is_taken = |n: &str| is_taken(n) || accepted.contains(n);
+                *greeting = session.greet(*greeting, &is_taken, op_tokens);
+                if let Greeting::Accepted = greeting {
+                    accepted.insert(session.name.clone());
+                }
+            }
+        }
+
+        pending
+            .drain_filter(|(_, greeting)| match greeting {
+                Greeting::Waiting | Greeting::Shaken => false,
+                Greeting::Accepted => true,
+                Greeting::Rejected(at) => at.elapsed().as_secs_f32() > 1.0,
+            })
+            .filter_map(|(session, greeting)| match greeting {
+                Greeting::Accepted => Some(session),
+                _ => None,
+            })
+    }
+}
+
+/// Sessions that have been sent away, kept around for a second so their goodbyes get delivered.
+pub struct Farewells(Vec<(Session, Instant)>);
+impl Farewells {
+    pub fn new() -> Self {
+        Self(Vec::with_capacity(10))
+    }
+
+    pub fn add(&mut self, session: Session) {
+        self.0.push((session, Instant::now()));
+    }
+
+    /// Drops any sessions that have been lingering for long enough.
+    pub fn prune(&mut self) {
+        self.0.retain(|(_, at)| at.elapsed().as_secs_f32() < 1.0);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
     }
 }
 
 /// A UDP socket that accepts new connections for as long as it's open.
-pub async fn open_socket(my_addr: &str, pool_size: usize, client_tx: SyncSender<Session>) {
-    use comn::net::{
-        acquire_max, channel_with_multiplexer, send_outgoing_to_socket, SimpleBufferPool,
+/// Packets to and from each client go across a simulated link with these conditions,
+/// unless they're perfect.
+pub async fn open_socket(
+    my_addr: String,
+    pool_size: usize,
+    timeout: Duration,
+    conditions: LinkConditions,
+    client_tx: SyncSender<Session>,
+) {
+    use comn::{
+        link::{random_seed, Inbox},
+        net::{acquire_max, channel_with_multiplexer, send_outgoing_to_socket, SimpleBufferPool},
     };
     use std::collections::HashMap;
     use turbulence::{BufferPacketPool, Packet};
@@ -37,25 +293,24 @@ pub async fn open_socket(my_addr: &str, pool_size: usize, client_tx: SyncSender<
     let pool = BufferPacketPool::new(SimpleBufferPool(pool_size));
     let mut sockets_incoming = HashMap::with_capacity(100);
 
-    let socket = smol::net::UdpSocket::bind(my_addr).await.expect("couldn't bind to address");
+    let socket = smol::net::UdpSocket::bind(&my_addr).await.expect("couldn't bind to address");
 
     loop {
         let mut packet = acquire_max(&pool);
         match socket.recv_from(&mut packet).await {
             Ok((len, addr)) => {
-                let incoming = sockets_incoming.entry(addr).or_insert_with(|| {
+                let inbox = sockets_incoming.entry(addr).or_insert_with(|| {
                     let (channel, multiplexer) = channel_with_multiplexer(pool.clone());
                     let (incoming, outgoing) = multiplexer.start();
-                    send_outgoing_to_socket(outgoing, socket.clone(), addr);
-                    client_tx.send(Session::new(channel, addr)).unwrap();
-                    incoming
+                    send_outgoing_to_socket(outgoing, socket.clone(), addr, conditions);
+                    client_tx.send(Session::new(channel, addr, timeout)).unwrap();
+                    Inbox::new(incoming, pool.clone(), conditions, random_seed())
                 });
                 packet.truncate(len);
-                use turbulence::packet_multiplexer::{IncomingError::*, IncomingTrySendError::*};
-                match incoming.try_send(packet) {
-                    Ok(()) => {}
-                    Err(Error(ChannelReceiverDropped)) => return,
-                    Err(e) => log::error!("couldn't send packet: {}", e),
+                comn::net::count_received(&packet);
+                // their Session is gone, so the next packet from them starts a new one
+                if !inbox.deliver(packet) {
+                    sockets_incoming.remove(&addr);
                 }
             }
             Err(e) => log::error!("couldn't recieve packet from UDP socket: {}", e),
@@ -0,0 +1,75 @@
+use crate::net::Session;
+use comn::{Comp, CompKind, EntEvent, WorldEvent};
+use glam::Vec2;
+
+/// The replicated components an entity had as of the last tick.
+struct Replica(Vec<Comp>);
+
+/// Finds the replicated components that have been added, changed or removed each tick,
+/// and tells the clients who can see them.
+pub struct Replicator {
+    /// The changes found this tick, and the ids of the entities they happened to.
+    changes: Vec<(u64, EntEvent)>,
+    /// Temporary buffer for an entity's current components.
+    gathered: Vec<Comp>,
+    /// Temporary buffer for entities that haven't been given a Replica yet.
+    need_replica: Vec<(hecs::Entity, Replica)>,
+}
+impl Replicator {
+    pub fn new() -> Self {
+        Self {
+            changes: Vec::with_capacity(100),
+            gathered: Vec::with_capacity(10),
+            need_replica: Vec::with_capacity(100),
+        }
+    }
+
+    pub fn track(&mut self, ecs: &mut hecs::World) {
+        let Self { changes, gathered, need_replica } = self;
+
+        // new entities are sent with all of their components when they're spawned
+        need_replica.extend(ecs.query::<&Vec2>().without::<Replica>().iter().map(|(e, _)| {
+            let mut comps = Vec::with_capacity(4);
+            comn::gather(ecs, e, &mut comps);
+            (e, Replica(comps))
+        }));
+        for (e, replica) in need_replica.drain(..) {
+            comn::or_err!(ecs.insert_one(e, replica));
+        }
+
+        changes.clear();
+        for (e, Replica(last)) in &mut ecs.query::<&mut Replica>() {
+            gathered.clear();
+            comn::gather(ecs, e, gathered);
+
+            let id = e.to_bits();
+            let updated: Vec<Comp> =
+                gathered.iter().filter(|&c| !last.contains(c)).cloned().collect();
+            let removed: Vec<CompKind> = last
+                .iter()
+                .map(Comp::kind)
+                .filter(|&kind| gathered.iter().all(|c| c.kind() != kind))
+                .collect();
+
+            if updated.is_empty() && removed.is_empty() {
+                continue;
+            }
+            if !updated.is_empty() {
+                changes.push((id, EntEvent::Update(id, updated)));
+            }
+            if !removed.is_empty() {
+                changes.push((id, EntEvent::Remove(id, removed)));
+            }
+            last.clone_from(gathered);
+        }
+    }
+
+    /// Tells the client about any changes to the entities they can see.
+    pub fn sync(&self, Session { channel, epoch, visible, .. }: &mut Session) {
+        for (id, change) in &self.changes {
+            if visible.contains(id) {
+                comn::send_or_err(channel, WorldEvent { epoch: *epoch, event: change.clone() });
+            }
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use crate::Revolve;
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Bump this whenever the layout of a WorldSave changes,
+/// and teach `read` how to bring saves in the old layout up to date.
+const SAVE_VERSION: u32 = 1;
+
+/// Everything about a World that should survive the server restarting.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct WorldSave {
+    pub version: u32,
+    pub name: String,
+    pub tick: u32,
+    pub ents: Vec<EntSave>,
+}
+impl WorldSave {
+    pub fn new(name: String, tick: u32, ents: Vec<EntSave>) -> Self {
+        Self { version: SAVE_VERSION, name, tick, ents }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct EntSave {
+    pub pos: Vec2,
+    /// Every replicated component is saved, so new ones don't need any extra work here.
+    pub comps: Vec<comn::Comp>,
+    pub revolve: Option<Revolve>,
+}
+
+/// Only the version, so that it can be checked before the rest of the save is parsed.
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// Where the save for the World with this name lives.
+fn path(dir: &str, world_name: &str) -> PathBuf {
+    let file: String =
+        world_name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    Path::new(dir).join(file).with_extension("ron")
+}
+
+/// Writes a save to disk, going through a temporary file so that
+/// a crash halfway through doesn't leave a corrupted save behind.
+pub fn write(dir: &str, save: &WorldSave) -> Result<(), String> {
+    let path = path(dir, &save.name);
+    let tmp = path.with_extension("ron.tmp");
+
+    let text = ron::ser::to_string_pretty(save, Default::default())
+        .map_err(|e| format!("couldn't serialize {}: {}", save.name, e))?;
+    std::fs::create_dir_all(dir).map_err(|e| format!("couldn't create {}: {}", dir, e))?;
+    std::fs::write(&tmp, text).map_err(|e| format!("couldn't write {}: {}", tmp.display(), e))?;
+    std::fs::rename(&tmp, &path).map_err(|e| format!("couldn't replace {}: {}", path.display(), e))
+}
+
+/// Reads the save for the World with this name, if there is one.
+pub fn read(dir: &str, world_name: &str) -> Result<Option<WorldSave>, String> {
+    let path = path(dir, world_name);
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("couldn't read {}: {}", path.display(), e)),
+    };
+
+    let Header { version } = ron::de::from_str(&text)
+        .map_err(|e| format!("couldn't read version of {}: {}", path.display(), e))?;
+    if version > SAVE_VERSION {
+        return Err(format!(
+            "{} is from a newer server, it has version {} but we only know up to {}",
+            path.display(),
+            version,
+            SAVE_VERSION
+        ));
+    }
+
+    ron::de::from_str(&text)
+        .map(Some)
+        .map_err(|e| format!("couldn't parse {}: {}", path.display(), e))
+}
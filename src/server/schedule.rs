@@ -0,0 +1,155 @@
+use serde::Deserialize;
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// What to do once ticks have been taking so long that the server has fallen behind.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Overrun {
+    /// Run the late ticks back to back until the server is back on schedule,
+    /// so that the worlds keep pace with the real world
+    CatchUp,
+    /// Drop the ticks that are already late, staying on the original schedule
+    Skip,
+    /// Start the schedule over from now, so the worlds run slower for as long as ticks overrun
+    SlowDown,
+}
+impl FromStr for Overrun {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "catch-up" => Ok(Overrun::CatchUp),
+            "skip" => Ok(Overrun::Skip),
+            "slow-down" => Ok(Overrun::SlowDown),
+            _ => Err("expected catch-up, skip or slow-down".to_string()),
+        }
+    }
+}
+
+/// How long ticks have been taking, for keeping an eye on the server.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TickStats {
+    pub ticks: u64,
+    /// How many ticks took longer than they were supposed to
+    pub overruns: u64,
+    /// How many ticks were dropped to get back on schedule, see `Overrun::Skip`
+    pub skipped: u64,
+    /// How long the newest tick took
+    pub last: Duration,
+    /// How long ticks have been taking lately, weighted towards the newest ones
+    pub average: Duration,
+    /// The longest any tick has taken
+    pub longest: Duration,
+    /// How far behind schedule the server was at the end of the newest tick
+    pub behind: Duration,
+}
+
+/// Decides when each tick should start, keeping track of how long they take.
+pub struct Scheduler {
+    tick: Duration,
+    policy: Overrun,
+    /// When the next tick should start
+    next: Instant,
+    pub stats: TickStats,
+    /// How many overruns there have been since the last time we complained about them
+    unreported: u64,
+    last_report: Instant,
+}
+impl Scheduler {
+    pub fn new(tick: Duration, policy: Overrun) -> Self {
+        Self {
+            tick,
+            policy,
+            next: Instant::now(),
+            stats: TickStats::default(),
+            unreported: 0,
+            last_report: Instant::now(),
+        }
+    }
+
+    /// Records how long the tick that `started` at has taken,
+    /// returning when the next one should start.
+    pub fn finish(&mut self, started: Instant) -> Instant {
+        self.finish_at(started, Instant::now())
+    }
+
+    fn finish_at(&mut self, started: Instant, now: Instant) -> Instant {
+        let Self { tick, policy, next, stats, unreported, last_report } = self;
+        let took = now - started;
+
+        stats.ticks += 1;
+        stats.last = took;
+        stats.longest = stats.longest.max(took);
+        stats.average =
+            if stats.ticks == 1 { took } else { stats.average.mul_f32(0.9) + took / 10 };
+        if took > *tick {
+            stats.overruns += 1;
+            *unreported += 1;
+        }
+
+        *next += *tick;
+        stats.behind = now.saturating_duration_since(*next);
+        if stats.behind > Duration::from_secs(0) {
+            match policy {
+                Overrun::CatchUp => {}
+                Overrun::Skip => {
+                    let (behind, tick_ns) = (stats.behind.as_nanos(), tick.as_nanos());
+                    let late = ((behind + tick_ns - 1) / tick_ns) as u32;
+                    *next += *tick * late;
+                    stats.skipped += late as u64;
+                }
+                Overrun::SlowDown => *next = now,
+            }
+        }
+
+        if *unreported > 0 && last_report.elapsed() > Duration::from_secs(5) {
+            log::warn!(
+                "{} ticks took longer than {:?} in the last {:.0?}, the longest took {:?}",
+                unreported,
+                tick,
+                last_report.elapsed(),
+                stats.longest,
+            );
+            *unreported = 0;
+            *last_report = Instant::now();
+        }
+
+        *next
+    }
+}
+
+#[test]
+fn overrun_policies() {
+    let tick = Duration::from_millis(50);
+    let start = Instant::now();
+    // a tick that starts on time but takes three and a half ticks to finish
+    let slow = |policy| {
+        let mut scheduler = Scheduler::new(tick, policy);
+        scheduler.next = start;
+        let next = scheduler.finish_at(start, start + tick * 7 / 2);
+        (next - start, scheduler.stats)
+    };
+
+    let (next, stats) = slow(Overrun::CatchUp);
+    assert_eq!(next, tick);
+    assert_eq!((stats.overruns, stats.skipped, stats.behind), (1, 0, tick * 5 / 2));
+
+    let (next, stats) = slow(Overrun::Skip);
+    assert_eq!(next, tick * 4);
+    assert_eq!((stats.overruns, stats.skipped), (1, 3));
+
+    let (next, stats) = slow(Overrun::SlowDown);
+    assert_eq!(next, tick * 7 / 2);
+    assert_eq!((stats.overruns, stats.skipped), (1, 0));
+
+    // ticks that finish in time are never rescheduled
+    for policy in [Overrun::CatchUp, Overrun::Skip, Overrun::SlowDown] {
+        let mut scheduler = Scheduler::new(tick, policy);
+        scheduler.next = start;
+        let next = scheduler.finish_at(start, start + tick / 2);
+        assert_eq!(next - start, tick);
+        assert_eq!(scheduler.stats.overruns, 0);
+    }
+}
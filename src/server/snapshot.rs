@@ -0,0 +1,86 @@
+use crate::net::Session;
+use comn::{Snapshot, SnapshotAck, SNAPSHOT_MOVES_PER_PART};
+use fxhash::FxHashMap;
+use glam::Vec2;
+use std::collections::VecDeque;
+
+/// How many ticks of snapshots are kept around to diff against, about three seconds worth.
+const HISTORY: usize = 64;
+
+/// Where everything in a World was on a particular tick.
+struct Frame {
+    tick: u32,
+    positions: FxHashMap<u64, Vec2>,
+}
+
+/// Remembers where everything was on each recent tick, so that clients can be sent
+/// only what has changed since the newest snapshot they've told us they received.
+pub struct Snapshots {
+    history: VecDeque<Frame>,
+    /// Temporary buffer for the movements a particular client needs to hear about.
+    moves: Vec<(u64, Vec2)>,
+}
+impl Snapshots {
+    pub fn new() -> Self {
+        Self { history: VecDeque::with_capacity(HISTORY), moves: Vec::with_capacity(1000) }
+    }
+
+    /// Records where everything is on this tick.
+    pub fn take(&mut self, ecs: &hecs::World, tick: u32) {
+        let mut positions = match self.history.len() {
+            HISTORY => self.history.pop_front().unwrap().positions,
+            _ => FxHashMap::default(),
+        };
+        positions.clear();
+        positions.extend(ecs.query::<&Vec2>().iter().map(|(e, &p)| (e.to_bits(), p)));
+        self.history.push_back(Frame { tick, positions });
+    }
+
+    /// Sends the client everything that has moved since the newest snapshot they acknowledged,
+    /// or everything there is if that snapshot is too old for us to still have it.
+    /// Only what the client can see is sent, see `Interest`.
+    pub fn sync(&mut self, session: &mut Session) {
+        let Session { channel, epoch, acked_snapshot, visible, .. } = session;
+        let Self { history, moves } = self;
+
+        while let Some(SnapshotAck { epoch: acked_epoch, tick }) = channel.recv() {
+            // an old ack may even turn up after the client has moved to another World
+            if acked_epoch == *epoch && acked_snapshot.map_or(true, |acked| tick > acked) {
+                *acked_snapshot = Some(tick);
+            }
+        }
+
+        let current = match history.back() {
+            Some(current) => current,
+            None => return,
+        };
+        let baseline = acked_snapshot.and_then(|acked| history.iter().find(|f| f.tick == acked));
+
+        moves.clear();
+        moves.extend(
+            current
+                .positions
+                .iter()
+                .filter(|(id, _)| visible.contains(id))
+                .filter(|(id, pos)| baseline.map_or(true, |b| b.positions.get(id) != Some(pos)))
+                .map(|(&id, &pos)| (id, pos)),
+        );
+
+        // even if nothing has moved, an empty Snapshot lets the client acknowledge this tick
+        let parts = ((moves.len() + SNAPSHOT_MOVES_PER_PART - 1) / SNAPSHOT_MOVES_PER_PART).max(1);
+        for part in 0..parts {
+            let start = part * SNAPSHOT_MOVES_PER_PART;
+            let end = (start + SNAPSHOT_MOVES_PER_PART).min(moves.len());
+            comn::send_or_err(
+                channel,
+                Snapshot {
+                    epoch: *epoch,
+                    tick: current.tick,
+                    part: part as u16,
+                    parts: parts as u16,
+                    moves: moves[start..end].to_vec(),
+                },
+            );
+        }
+    }
+}